@@ -1,30 +1,136 @@
 mod server;
 
-use crate::server::state::State;
+use crate::server::{
+    access::AccessLog,
+    audit::{AuditSink, FileAuditSink, NoopAuditSink},
+    config::*,
+    error::Result,
+    state::State,
+};
 
-use std::{env, io::Error};
+use std::{
+    env,
+    fmt::Display,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use tracing::{debug, info, level_filters::LevelFilter, warn};
-use tracing_subscriber::EnvFilter;
+use tracing::{Dispatch, debug, error, info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{EnvFilter, util::SubscriberInitExt};
 
-const DEFAULT_SERVER_HOST: &str = "localhost";
-const DEFAULT_SERVER_PORT: &str = "9000";
+const DEFAULT_LOG_FORMAT: &str = "json";
+
+/// Reads and parses the environment variable `name`, falling back to `default` when it's unset or
+/// invalid.
+fn parse_var<T>(name: &str, default: T) -> T
+where
+    T: FromStr + Display,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => match value.parse::<T>() {
+            Ok(value) => {
+                debug!(value = %value, "{} defined", name);
+
+                value
+            }
+            Err(e) => {
+                warn!(
+                    value = %default,
+                    error = e.to_string(),
+                    "{} is invalid, using default",
+                    name,
+                );
+
+                default
+            }
+        },
+        Err(_) => {
+            warn!(value = %default, "{} not set, using default", name);
+
+            default
+        }
+    }
+}
+
+/// Reads the environment variable `name` as a comma-separated list, which is empty when unset.
+fn parse_list(name: &str) -> Vec<String> {
+    match env::var(name) {
+        Ok(value) => {
+            debug!(value = value, "{} defined", name);
+
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect()
+        }
+        Err(_) => {
+            debug!("{} not set, using default", name);
+
+            Vec::new()
+        }
+    }
+}
+
+/// Builds the log filter from the directives of the `LOG` variable, falling back to the `INFO`
+/// level when they're invalid so a misconfigured deploy still starts.
+fn log_filter(directives: &str) -> EnvFilter {
+    let builder = EnvFilter::builder().with_default_directive(LevelFilter::INFO.into());
+
+    match builder.parse(directives) {
+        Ok(filter) => filter,
+        Err(e) => {
+            // Nothing logs yet, so the warning can only go to stderr.
+            eprintln!("LOG is invalid, using the info level: {}", e);
+
+            EnvFilter::new(LevelFilter::INFO.to_string())
+        }
+    }
+}
+
+/// Builds the subscriber writing the logs in the format, `pretty` or `json`, which is used for any
+/// other value.
+fn log_subscriber(format: &str, filter: EnvFilter) -> Dispatch {
+    return match format {
+        "pretty" => Dispatch::new(
+            tracing_subscriber::fmt()
+                .with_env_filter(filter)
+                .pretty()
+                .finish(),
+        ),
+        _ => Dispatch::new(
+            tracing_subscriber::fmt()
+                .with_env_filter(filter)
+                .json()
+                .finish(),
+        ),
+    };
+}
+
+/// Logs the configuration the server runs with, so operators can check what it resolved to.
+fn log_config(config: &Config) {
+    info!(config = ?config, "configuration resolved");
+}
 
 #[tokio::main]
-async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            EnvFilter::builder()
-                .with_env_var("LOG")
-                .with_default_directive(LevelFilter::INFO.into())
-                .from_env()
-                .unwrap(),
-        )
-        .json()
-        .init();
+async fn main() -> Result<()> {
+    let filter = log_filter(&env::var("LOG").unwrap_or_default());
+
+    let format = env::var("TELEVIU_LOG_FORMAT").unwrap_or_else(|_| DEFAULT_LOG_FORMAT.to_string());
+
+    log_subscriber(&format, filter).init();
 
     info!("televiu server started");
 
+    if format != "json" && format != "pretty" {
+        warn!(value = format, "TELEVIU_LOG_FORMAT is invalid, using json");
+    }
+
     let host = match env::var("TELEVIU_SERVER_HOST") {
         Ok(addr) => {
             debug!(value = addr, "TELEVIU_SERVER_HOST defined");
@@ -57,9 +163,427 @@ async fn main() -> Result<(), Error> {
         }
     };
 
-    let state = State::new();
+    let addresses = match env::var("TELEVIU_SERVER_ADDRESSES") {
+        Ok(addresses) => {
+            debug!(value = addresses, "TELEVIU_SERVER_ADDRESSES defined");
+
+            addresses
+                .split(',')
+                .map(str::trim)
+                .filter(|addr| !addr.is_empty())
+                .filter_map(|addr| match addr.parse::<SocketAddr>() {
+                    Ok(addr) => Some(addr),
+                    Err(e) => {
+                        warn!(
+                            value = addr,
+                            error = e.to_string(),
+                            "invalid address in TELEVIU_SERVER_ADDRESSES, skipping",
+                        );
+
+                        None
+                    }
+                })
+                .collect()
+        }
+        Err(_) => Vec::new(),
+    };
+
+    let origins = parse_list("TELEVIU_CORS_ORIGINS");
+    let player_origins = parse_list("TELEVIU_PLAYER_CORS_ORIGINS");
+    let controller_origins = parse_list("TELEVIU_CONTROLLER_CORS_ORIGINS");
+
+    // An empty list keeps the default of allowing GET only.
+    let cors_methods = match parse_list("TELEVIU_CORS_METHODS") {
+        methods if methods.is_empty() => vec!["GET".to_string()],
+        methods => methods,
+    };
+    let cors_headers = parse_list("TELEVIU_CORS_HEADERS");
+    let cors_credentials = parse_var("TELEVIU_CORS_CREDENTIALS", false);
+
+    let body_limit = parse_var("TELEVIU_REQUEST_BODY_LIMIT", DEFAULT_REQUEST_BODY_LIMIT);
+
+    let ping_interval = Duration::from_secs(parse_var(
+        "TELEVIU_PING_INTERVAL",
+        DEFAULT_PING_INTERVAL_SECS,
+    ));
+
+    let ping_timeout = Duration::from_secs(parse_var(
+        "TELEVIU_PING_TIMEOUT",
+        DEFAULT_PING_TIMEOUT_SECS,
+    ));
+
+    let drain_timeout = Duration::from_secs(parse_var(
+        "TELEVIU_DRAIN_TIMEOUT",
+        DEFAULT_DRAIN_TIMEOUT_SECS,
+    ));
+
+    let connections_per_minute = parse_var(
+        "TELEVIU_CONNECTIONS_PER_MINUTE",
+        DEFAULT_CONNECTIONS_PER_MINUTE,
+    );
+
+    let reconnect_grace = Duration::from_secs(parse_var(
+        "TELEVIU_RECONNECT_GRACE",
+        DEFAULT_RECONNECT_GRACE_SECS,
+    ));
+
+    let idle_timeout = Duration::from_secs(parse_var(
+        "TELEVIU_IDLE_TIMEOUT",
+        DEFAULT_IDLE_TIMEOUT_SECS,
+    ));
+
+    let max_message_size = parse_var("TELEVIU_MAX_MESSAGE_SIZE", DEFAULT_MAX_MESSAGE_SIZE);
+
+    let channel_capacity = parse_var("TELEVIU_CHANNEL_CAPACITY", DEFAULT_CHANNEL_CAPACITY);
+
+    let max_devices = parse_var("TELEVIU_MAX_DEVICES", DEFAULT_MAX_DEVICES);
+
+    let request_id_header = match env::var("TELEVIU_REQUEST_ID_HEADER") {
+        Ok(header) => {
+            debug!(value = header, "TELEVIU_REQUEST_ID_HEADER defined");
+
+            header
+        }
+        Err(_) => {
+            debug!(
+                value = DEFAULT_REQUEST_ID_HEADER,
+                "TELEVIU_REQUEST_ID_HEADER not set, using default",
+            );
+
+            DEFAULT_REQUEST_ID_HEADER.to_string()
+        }
+    };
+
+    let reap_interval = Duration::from_secs(parse_var(
+        "TELEVIU_REAP_INTERVAL",
+        DEFAULT_REAP_INTERVAL_SECS,
+    ));
+
+    let write_timeout = Duration::from_secs(parse_var(
+        "TELEVIU_WRITE_TIMEOUT",
+        DEFAULT_WRITE_TIMEOUT_SECS,
+    ));
+
+    let compression_level = match env::var("TELEVIU_COMPRESSION_LEVEL") {
+        Ok(level) => match level.parse::<i32>() {
+            Ok(level) => {
+                debug!(value = level, "TELEVIU_COMPRESSION_LEVEL defined");
+
+                Some(level)
+            }
+            Err(e) => {
+                warn!(
+                    error = e.to_string(),
+                    "TELEVIU_COMPRESSION_LEVEL is invalid, using default",
+                );
+
+                None
+            }
+        },
+        Err(_) => None,
+    };
+
+    let compression_min_size = parse_var(
+        "TELEVIU_COMPRESSION_MIN_SIZE",
+        DEFAULT_COMPRESSION_MIN_SIZE,
+    );
+
+    let compression_content_types = parse_list("TELEVIU_COMPRESSION_CONTENT_TYPES");
+
+    let id_format = parse_var("TELEVIU_ID_FORMAT", IdFormat::default());
+
+    let mut play_schemes = parse_list("TELEVIU_PLAY_SCHEMES");
+
+    if play_schemes.is_empty() {
+        play_schemes = DEFAULT_PLAY_SCHEMES.iter().map(|s| s.to_string()).collect();
+    }
+
+    let access_log = match parse_var("TELEVIU_ACCESS_LOG", false) {
+        true => Some(Arc::new(AccessLog::stdout())),
+        false => None,
+    };
+
+    let tls_cert = match env::var("TELEVIU_TLS_CERT") {
+        Ok(path) => {
+            debug!(value = path, "TELEVIU_TLS_CERT defined");
+
+            Some(PathBuf::from(path))
+        }
+        Err(_) => None,
+    };
+
+    let tls_key = match env::var("TELEVIU_TLS_KEY") {
+        Ok(path) => {
+            debug!(value = path, "TELEVIU_TLS_KEY defined");
+
+            Some(PathBuf::from(path))
+        }
+        Err(_) => None,
+    };
+
+    let audit: Arc<dyn AuditSink> = match env::var("TELEVIU_AUDIT_LOG") {
+        Ok(path) => {
+            debug!(value = path, "TELEVIU_AUDIT_LOG defined");
+
+            match FileAuditSink::open(&path) {
+                Ok(sink) => Arc::new(sink),
+                Err(e) => {
+                    error!(error = e.to_string(), "failed to open the audit log");
+
+                    return Err(e.into());
+                }
+            }
+        }
+        Err(_) => Arc::new(NoopAuditSink),
+    };
+
+    let admin_token = match env::var("TELEVIU_ADMIN_TOKEN") {
+        Ok(token) => {
+            debug!("TELEVIU_ADMIN_TOKEN defined");
+
+            Some(token)
+        }
+        Err(_) => {
+            warn!("TELEVIU_ADMIN_TOKEN not set, administration routes are disabled");
+
+            None
+        }
+    };
+
+    let max_frame_size = parse_var("TELEVIU_MAX_FRAME_SIZE", DEFAULT_MAX_FRAME_SIZE);
+
+    let max_ws_message_size =
+        parse_var("TELEVIU_MAX_WS_MESSAGE_SIZE", DEFAULT_MAX_WS_MESSAGE_SIZE);
+
+    let bind_retries = parse_var("TELEVIU_BIND_RETRIES", DEFAULT_BIND_RETRIES);
+
+    let bind_retry_delay = Duration::from_millis(parse_var(
+        "TELEVIU_BIND_RETRY_DELAY",
+        DEFAULT_BIND_RETRY_DELAY_MILLIS,
+    ));
+
+    let commands_per_second =
+        parse_var("TELEVIU_COMMANDS_PER_SECOND", DEFAULT_COMMANDS_PER_SECOND);
+
+    let handshake_timeout = Duration::from_secs(parse_var(
+        "TELEVIU_HANDSHAKE_TIMEOUT",
+        DEFAULT_HANDSHAKE_TIMEOUT_SECS,
+    ));
+
+    let routes = match env::var("TELEVIU_ROUTE_PREFIX") {
+        Ok(prefix) => {
+            debug!(value = prefix, "TELEVIU_ROUTE_PREFIX defined");
+
+            RouteConfig {
+                prefix,
+                ..RouteConfig::default()
+            }
+        }
+        Err(_) => RouteConfig::default(),
+    };
+
+    let ack_timeout = Duration::from_secs(parse_var(
+        "TELEVIU_ACK_TIMEOUT",
+        DEFAULT_ACK_TIMEOUT_SECS,
+    ));
+
+    // An empty list leaves every command allowed.
+    let allowed_commands = match parse_commands(parse_list("TELEVIU_ALLOWED_COMMANDS")) {
+        Ok(commands) => commands,
+        Err(e) => {
+            error!(error = e.to_string(), "invalid TELEVIU_ALLOWED_COMMANDS");
+
+            return Err(e.into());
+        }
+    };
+
+    let backlog = parse_var("TELEVIU_BACKLOG", DEFAULT_BACKLOG);
+
+    let tcp_keepalive = Duration::from_secs(parse_var(
+        "TELEVIU_TCP_KEEPALIVE",
+        DEFAULT_TCP_KEEPALIVE_SECS,
+    ));
+
+    let enforce_seq = parse_var("TELEVIU_ENFORCE_SEQ", false);
+
+    let controller_queue = match env::var("TELEVIU_CONTROLLER_QUEUE") {
+        Ok(depth) => match depth.parse::<usize>() {
+            Ok(depth) => {
+                debug!(value = depth, "TELEVIU_CONTROLLER_QUEUE defined");
+
+                Some(depth)
+            }
+            Err(e) => {
+                warn!(
+                    error = e.to_string(),
+                    "TELEVIU_CONTROLLER_QUEUE is invalid, using default",
+                );
+
+                None
+            }
+        },
+        Err(_) => None,
+    };
+
+    let app_ping_interval = Duration::from_secs(parse_var("TELEVIU_APP_PING_INTERVAL", 0));
+
+    let max_session_duration = Duration::from_secs(parse_var("TELEVIU_MAX_SESSION_DURATION", 0));
+
+    let pair_policy = parse_var("TELEVIU_PAIR_POLICY", PairPolicy::default());
+
+    let compression_enabled = parse_var("TELEVIU_COMPRESSION", true);
+
+    let trusted_proxies = match env::var("TELEVIU_TRUSTED_PROXIES") {
+        Ok(proxies) => {
+            debug!(value = proxies, "TELEVIU_TRUSTED_PROXIES defined");
+
+            proxies
+                .split(',')
+                .map(str::trim)
+                .filter(|proxy| !proxy.is_empty())
+                .filter_map(|proxy| match proxy.parse::<IpAddr>() {
+                    Ok(proxy) => Some(proxy),
+                    Err(e) => {
+                        warn!(
+                            value = proxy,
+                            error = e.to_string(),
+                            "invalid address in TELEVIU_TRUSTED_PROXIES, skipping",
+                        );
+
+                        None
+                    }
+                })
+                .collect()
+        }
+        Err(_) => Vec::new(),
+    };
+
+    let echo_enabled = parse_var("TELEVIU_ECHO_ENABLED", false);
+
+    let config = match Config::builder()
+        .host(host)
+        .port(port)
+        .addresses(addresses)
+        .origins(origins)
+        .player_origins(player_origins)
+        .controller_origins(controller_origins)
+        .cors_methods(cors_methods)
+        .cors_headers(cors_headers)
+        .cors_credentials(cors_credentials)
+        .body_limit(body_limit)
+        .ping_interval(ping_interval)
+        .ping_timeout(ping_timeout)
+        .admin_token(admin_token)
+        .drain_timeout(drain_timeout)
+        .connections_per_minute(connections_per_minute)
+        .reconnect_grace(reconnect_grace)
+        .idle_timeout(idle_timeout)
+        .max_message_size(max_message_size)
+        .channel_capacity(channel_capacity)
+        .max_devices(max_devices)
+        .request_id_header(request_id_header)
+        .reap_interval(reap_interval)
+        .tls_cert(tls_cert)
+        .tls_key(tls_key)
+        .audit(audit)
+        .write_timeout(write_timeout)
+        .compression_level(compression_level)
+        .compression_min_size(compression_min_size)
+        .compression_content_types(compression_content_types)
+        .id_format(id_format)
+        .play_schemes(play_schemes)
+        .access_log(access_log)
+        .max_frame_size(max_frame_size)
+        .max_ws_message_size(max_ws_message_size)
+        .bind_retries(bind_retries)
+        .bind_retry_delay(bind_retry_delay)
+        .commands_per_second(commands_per_second)
+        .handshake_timeout(handshake_timeout)
+        .routes(routes)
+        .ack_timeout(ack_timeout)
+        .allowed_commands(allowed_commands)
+        .backlog(backlog)
+        .tcp_keepalive(tcp_keepalive)
+        .enforce_seq(enforce_seq)
+        .controller_queue(controller_queue)
+        .app_ping_interval(app_ping_interval)
+        .max_session_duration(max_session_duration)
+        .pair_policy(pair_policy)
+        .compression_enabled(compression_enabled)
+        .trusted_proxies(trusted_proxies)
+        .echo_enabled(echo_enabled)
+        .build()
+    {
+        Ok(config) => config,
+        Err(e) => {
+            error!(error = e.to_string(), "invalid server configuration");
+
+            return Err(e.into());
+        }
+    };
+
+    log_config(&config);
+
+    let state = Arc::new(State::new());
+
+    let router = server::router(state.clone(), &config).await;
+
+    return server::listen(router, config, state).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::Buffer;
+
+    #[test]
+    fn both_log_formats_initialize() {
+        for format in ["json", "pretty"] {
+            let subscriber = log_subscriber(format, log_filter("info"));
+
+            tracing::dispatcher::with_default(&subscriber, || {
+                info!(format = format, "log format initialized");
+            });
+        }
+    }
+
+    #[test]
+    fn invalid_log_directives_fall_back_to_info() {
+        let filter = log_filter("televiu=loud");
+
+        assert_eq!(filter.max_level_hint(), Some(LevelFilter::INFO));
+
+        let subscriber = tracing_subscriber::fmt().with_env_filter(filter).finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(tracing::enabled!(tracing::Level::INFO));
+            assert!(!tracing::enabled!(tracing::Level::DEBUG));
+        });
+    }
+
+    #[test]
+    fn startup_config_log_redacts_secrets() {
+        let config = Config::builder()
+            .port("9123")
+            .admin_token(Some("hunter2-token".into()))
+            .build()
+            .unwrap();
+
+        let buffer = Buffer::default();
+
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || log_config(&config));
 
-    let router = server::router(state).await;
+        let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
 
-    return server::listen(router, server::Config { host, port }).await;
+        assert_eq!(logs.lines().count(), 1);
+        assert!(logs.contains("port: 9123"));
+        assert!(logs.contains("***"));
+        assert!(!logs.contains("hunter2-token"));
+    }
 }
@@ -0,0 +1,128 @@
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::HttpBody,
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, header},
+    middleware::Next,
+    response::Response,
+};
+use tracing::error;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Writes an access log line in the Combined Log Format for every HTTP request, separately from
+/// the tracing output.
+pub struct AccessLog {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    pub fn stdout() -> Self {
+        return Self::new(io::stdout());
+    }
+
+    fn write(&self, line: &str) {
+        let mut writer = self.writer.lock().unwrap();
+
+        if let Err(e) = writeln!(writer, "{}", line).and_then(|_| writer.flush()) {
+            error!(error = e.to_string(), "failed to write access log");
+        }
+    }
+}
+
+/// Formats the time as in the Combined Log Format, in UTC.
+pub fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+    let (days, secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+
+    // Converts the days since the epoch to a civil date, see
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    return format!(
+        "[{:02}/{}/{}:{:02}:{:02}:{:02} +0000]",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+    );
+}
+
+/// Reads a header for the log line, which shows missing values as `-`.
+fn header_value(headers: &HeaderMap, name: header::HeaderName) -> String {
+    return headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("-")
+        .to_string();
+}
+
+/// Logs the request once its response is ready, with the time taken in microseconds appended to
+/// the Combined Log Format fields.
+///
+/// Only the path of the request is logged since controllers pass their secret in the query.
+pub async fn log(State(access): State<Arc<AccessLog>>, request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let time = SystemTime::now();
+
+    let host = match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => addr.ip().to_string(),
+        None => "-".to_string(),
+    };
+
+    let line = format!(
+        "{} {} {:?}",
+        request.method(),
+        request.uri().path(),
+        request.version()
+    );
+    let referer = header_value(request.headers(), header::REFERER);
+    let user_agent = header_value(request.headers(), header::USER_AGENT);
+
+    let response = next.run(request).await;
+
+    let size = match response.body().size_hint().exact() {
+        Some(size) => size.to_string(),
+        None => "-".to_string(),
+    };
+
+    let duration = start.elapsed();
+
+    access.write(&format!(
+        "{} - - {} \"{}\" {} {} \"{}\" \"{}\" {}",
+        host,
+        timestamp(time),
+        line,
+        response.status().as_u16(),
+        size,
+        referer,
+        user_agent,
+        duration.as_micros(),
+    ));
+
+    return response;
+}
@@ -0,0 +1,73 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::json;
+use tracing::error;
+
+use crate::server::protocol::Command;
+
+/// Command forwarded to a player, as recorded in the audit trail.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub device: String,
+    pub command: Command,
+    pub timestamp: SystemTime,
+    /// IP of the controller that sent the command.
+    pub ip: IpAddr,
+}
+
+/// Destination of the audit trail, called after every command forwarded to a player.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+/// Sink discarding every record, used when no audit trail is configured.
+pub struct NoopAuditSink;
+
+impl AuditSink for NoopAuditSink {
+    fn record(&self, _: &AuditRecord) {}
+}
+
+/// Sink appending the records to a file, one JSON object per line.
+pub struct FileAuditSink {
+    file: Mutex<File>,
+}
+
+impl FileAuditSink {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        return Ok(Self {
+            file: Mutex::new(file),
+        });
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn record(&self, record: &AuditRecord) {
+        let timestamp = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+
+        let line = json!({
+            "timestamp": timestamp,
+            "device": record.device,
+            "command": record.command,
+            "ip": record.ip,
+        });
+
+        let mut file = self.file.lock().unwrap();
+
+        if let Err(e) = writeln!(file, "{}", line) {
+            error!(error = e.to_string(), "failed to write audit record");
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::{collections::HashMap, sync::RwLock};
+
+use futures::future::{self, BoxFuture};
+
+use crate::server::secret::Secret;
+
+/// Issues the secrets of the devices and verifies the ones controllers present, so the secrets
+/// can live in a store shared by several instances of the server.
+pub trait AuthProvider: Send + Sync {
+    /// Issues the secret controllers must present to control the device.
+    ///
+    /// It's called once the name of the device is reserved, without holding the lock of the
+    /// registry, so a slow provider only holds up the registration it's called for.
+    fn issue<'a>(&'a self, device: &'a str) -> BoxFuture<'a, Secret>;
+
+    /// Checks the secret a controller presents for the device.
+    fn verify<'a>(&'a self, device: &'a str, secret: &'a Secret) -> BoxFuture<'a, bool>;
+
+    /// Forgets the secret of a device that was removed, which does nothing by default.
+    fn revoke<'a>(&'a self, _device: &'a str) -> BoxFuture<'a, ()> {
+        return Box::pin(future::ready(()));
+    }
+}
+
+/// Provider keeping the secrets in memory, used unless another one is given.
+#[derive(Default)]
+pub struct MemoryAuthProvider {
+    secrets: RwLock<HashMap<String, Secret>>,
+}
+
+impl AuthProvider for MemoryAuthProvider {
+    fn issue<'a>(&'a self, device: &'a str) -> BoxFuture<'a, Secret> {
+        let secret = Secret::generate();
+
+        self.secrets
+            .write()
+            .unwrap()
+            .insert(device.to_string(), secret.clone());
+
+        return Box::pin(future::ready(secret));
+    }
+
+    fn verify<'a>(&'a self, device: &'a str, secret: &'a Secret) -> BoxFuture<'a, bool> {
+        let valid = self.secrets.read().unwrap().get(device) == Some(secret);
+
+        return Box::pin(future::ready(valid));
+    }
+
+    fn revoke<'a>(&'a self, device: &'a str) -> BoxFuture<'a, ()> {
+        self.secrets.write().unwrap().remove(device);
+
+        return Box::pin(future::ready(()));
+    }
+}
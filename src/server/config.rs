@@ -0,0 +1,950 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::http::{HeaderName, Method};
+use rand::Rng;
+use url::Url;
+
+use crate::server::{
+    access::AccessLog,
+    audit::{AuditSink, NoopAuditSink},
+    protocol::{CONTROLLER_COMMANDS, Command},
+};
+
+pub const DEFAULT_SERVER_HOST: &str = "localhost";
+pub const DEFAULT_SERVER_PORT: &str = "9000";
+pub const DEFAULT_REQUEST_BODY_LIMIT: usize = 16 * 1024;
+pub const DEFAULT_PING_INTERVAL_SECS: u64 = 30;
+pub const DEFAULT_PING_TIMEOUT_SECS: u64 = 90;
+pub const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_CONNECTIONS_PER_MINUTE: u32 = 60;
+pub const DEFAULT_RECONNECT_GRACE_SECS: u64 = 30;
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 15 * 60;
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024;
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 100;
+pub const DEFAULT_MAX_DEVICES: usize = 10_000;
+pub const DEFAULT_REQUEST_ID_HEADER: &str = "x-request-id";
+pub const DEFAULT_REAP_INTERVAL_SECS: u64 = 60;
+pub const DEFAULT_WRITE_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_COMPRESSION_MIN_SIZE: u16 = 32;
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+pub const DEFAULT_MAX_WS_MESSAGE_SIZE: usize = 64 * 1024;
+pub const DEFAULT_BIND_RETRIES: u32 = 5;
+pub const DEFAULT_BIND_RETRY_DELAY_MILLIS: u64 = 100;
+pub const DEFAULT_COMMANDS_PER_SECOND: u32 = 20;
+pub const DEFAULT_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_ACK_TIMEOUT_SECS: u64 = 10;
+pub const DEFAULT_BACKLOG: u32 = 1024;
+pub const DEFAULT_TCP_KEEPALIVE_SECS: u64 = 60;
+pub const DEFAULT_PLAY_SCHEMES: &[&str] = &["http", "https"];
+
+/// Characters of the short device ids, leaving out the ones easily mistaken for each other.
+const SHORT_ID_CHARSET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const SHORT_ID_LENGTH: usize = 6;
+
+/// Format of the generated device ids.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum IdFormat {
+    /// Random UUID v4, practically unique.
+    #[default]
+    Uuid,
+    /// Six alphanumeric characters, easy to type in a pairing screen but likely to collide.
+    Short,
+}
+
+impl IdFormat {
+    /// Whether the code could be a short id, as generated by [`IdFormat::Short`].
+    pub fn is_short(code: &str) -> bool {
+        return code.len() == SHORT_ID_LENGTH
+            && code.bytes().all(|c| SHORT_ID_CHARSET.contains(&c));
+    }
+
+    pub fn generate(&self) -> String {
+        return match self {
+            IdFormat::Uuid => uuid::Uuid::new_v4().to_string(),
+            IdFormat::Short => {
+                let mut rng = rand::rng();
+
+                (0..SHORT_ID_LENGTH)
+                    .map(|_| SHORT_ID_CHARSET[rng.random_range(0..SHORT_ID_CHARSET.len())] as char)
+                    .collect()
+            }
+        };
+    }
+}
+
+impl fmt::Display for IdFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            IdFormat::Uuid => write!(f, "uuid"),
+            IdFormat::Short => write!(f, "short"),
+        };
+    }
+}
+
+impl FromStr for IdFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.trim().to_lowercase().as_str() {
+            "uuid" => Ok(IdFormat::Uuid),
+            "short" => Ok(IdFormat::Short),
+            other => Err(format!("unknown id format {:?}", other)),
+        };
+    }
+}
+
+/// What happens when a paired controller sends `Pair` again.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PairPolicy {
+    /// Drops the duplicate and keeps the session as it is.
+    #[default]
+    Ignore,
+    /// Releases the player and closes the controller with `AlreadyPaired`.
+    Error,
+    /// Releases the player and pairs again from a clean state.
+    Replace,
+}
+
+impl fmt::Display for PairPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            PairPolicy::Ignore => write!(f, "ignore"),
+            PairPolicy::Error => write!(f, "error"),
+            PairPolicy::Replace => write!(f, "replace"),
+        };
+    }
+}
+
+impl FromStr for PairPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        return match s.trim().to_lowercase().as_str() {
+            "ignore" => Ok(PairPolicy::Ignore),
+            "error" => Ok(PairPolicy::Error),
+            "replace" => Ok(PairPolicy::Replace),
+            other => Err(format!("unknown pair policy {:?}", other)),
+        };
+    }
+}
+
+/// Paths the routes are served at, where the probes always stay at the root.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteConfig {
+    /// Prefix of the routes, like `/api/v1`, where empty serves them at the root.
+    pub prefix: String,
+    pub controller: String,
+    pub player: String,
+    pub admin: String,
+}
+
+impl Default for RouteConfig {
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            controller: "/ws/controller".to_string(),
+            player: "/ws/player".to_string(),
+            admin: "/ws/admin".to_string(),
+        }
+    }
+}
+
+impl RouteConfig {
+    /// Returns the path of the route under the prefix.
+    pub fn path(&self, route: &str) -> String {
+        return format!("{}{}", self.prefix.trim_end_matches('/'), route);
+    }
+
+    /// Returns the first path that doesn't start with a `/`, which the router can't serve.
+    fn invalid(&self) -> Option<&str> {
+        if !self.prefix.is_empty() && !self.prefix.starts_with('/') {
+            return Some(&self.prefix);
+        }
+
+        return [&self.controller, &self.player, &self.admin]
+            .into_iter()
+            .find(|route| !route.starts_with('/'))
+            .map(|route| route.as_str());
+    }
+}
+
+/// Whether the origin is a scheme and host, with an optional port, written the way browsers send
+/// it in the `Origin` header.
+fn valid_origin(origin: &str) -> bool {
+    return match Url::parse(origin) {
+        Ok(url) => url.origin().is_tuple() && url.origin().ascii_serialization() == origin,
+        Err(_) => false,
+    };
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    /// Addresses to listen on in addition to the ones the host resolves to.
+    ///
+    /// On most systems `[::]` already accepts IPv4 connections, so listing it along with
+    /// `0.0.0.0` on the same port fails with the address in use.
+    pub addresses: Vec<SocketAddr>,
+    /// Origins allowed by the CORS layer.
+    pub origins: Vec<String>,
+    /// Origins allowed on the player route, defaulting to `origins` when empty.
+    pub player_origins: Vec<String>,
+    /// Origins allowed on the controller route, defaulting to `origins` when empty.
+    pub controller_origins: Vec<String>,
+    /// Methods allowed in cross-origin requests.
+    pub cors_methods: Vec<Method>,
+    /// Headers allowed in cross-origin requests, like `authorization`, in addition to the ones
+    /// browsers always allow.
+    pub cors_headers: Vec<HeaderName>,
+    /// Whether cross-origin requests may carry credentials, like cookies or an `Authorization`
+    /// header.
+    pub cors_credentials: bool,
+    /// Maximum size, in bytes, of a request body.
+    pub body_limit: usize,
+    /// Interval between the pings sent to players.
+    pub ping_interval: Duration,
+    /// Time without hearing from a player before its connection is considered dead.
+    pub ping_timeout: Duration,
+    /// Token required by the administration routes, which are disabled when unset.
+    pub admin_token: Option<String>,
+    /// Time given to active sessions to close before the server exits.
+    pub drain_timeout: Duration,
+    /// WebSocket connections allowed per client IP per minute, where zero disables the limit.
+    pub connections_per_minute: u32,
+    /// Time a disconnected player has to reconnect before its device is removed, where zero
+    /// removes it immediately.
+    pub reconnect_grace: Duration,
+    /// Time a controller can stay silent before its session is closed.
+    pub idle_timeout: Duration,
+    /// Maximum size, in bytes, of a message sent by a controller.
+    pub max_message_size: usize,
+    /// Commands queued for a player before new ones are rejected.
+    ///
+    /// Each device holds up to `channel_capacity` commands of up to `max_message_size` bytes, so a
+    /// deeper channel rides out slower links at the cost of memory.
+    pub channel_capacity: usize,
+    /// Devices registered at once before new players are turned away.
+    pub max_devices: usize,
+    /// Header carrying the request id, generated when the request doesn't have one.
+    pub request_id_header: HeaderName,
+    /// Interval between the scans for orphaned devices, where zero disables them.
+    pub reap_interval: Duration,
+    /// PEM certificate chain served over TLS, along with `tls_key`.
+    pub tls_cert: Option<PathBuf>,
+    /// PEM private key of the certificate, along with `tls_cert`.
+    pub tls_key: Option<PathBuf>,
+    /// Sink recording every command forwarded to a player.
+    pub audit: Arc<dyn AuditSink>,
+    /// Time a message can take to be sent before the connection is considered dead.
+    pub write_timeout: Duration,
+    /// Compression quality, where `None` uses the default of each algorithm.
+    pub compression_level: Option<i32>,
+    /// Size, in bytes, below which responses aren't compressed.
+    pub compression_min_size: u16,
+    /// Prefixes of the content types to compress, where empty compresses every type.
+    pub compression_content_types: Vec<String>,
+    /// Format of the generated device ids.
+    pub id_format: IdFormat,
+    /// URL schemes allowed in the payload of `Play` commands.
+    pub play_schemes: Vec<String>,
+    /// Log of the HTTP requests in the Combined Log Format, disabled when unset.
+    pub access_log: Option<Arc<AccessLog>>,
+    /// Maximum size, in bytes, of a WebSocket frame, above which the transport drops the connection.
+    pub max_frame_size: usize,
+    /// Maximum size, in bytes, of a WebSocket message reassembled from its frames, above which the
+    /// transport drops the connection before the handlers see it.
+    pub max_ws_message_size: usize,
+    /// Attempts to bind an address in use again before giving up, waiting twice as long each time.
+    pub bind_retries: u32,
+    /// Time waited before the first attempt to bind again, randomized to spread the attempts.
+    pub bind_retry_delay: Duration,
+    /// Commands a device accepts per second from its controllers, in bursts of as many, where zero
+    /// disables the limit.
+    pub commands_per_second: u32,
+    /// Time a connection has to send its request and get the upgrade, or any response, before it's
+    /// closed, where zero waits forever.
+    pub handshake_timeout: Duration,
+    /// Paths of the routes, for mounting the server next to other routes.
+    pub routes: RouteConfig,
+    /// Time the player has to acknowledge a command carrying an id before the controller gets a
+    /// timeout `Nack` for it, where zero waits forever.
+    pub ack_timeout: Duration,
+    /// Commands controllers may send, the others closing their connection, which allows all of them
+    /// by default.
+    pub allowed_commands: HashSet<Command>,
+    /// Connections the listeners queue while they wait to be accepted.
+    pub backlog: u32,
+    /// Time a connection stays idle before TCP keepalive probes it, where zero disables them.
+    pub tcp_keepalive: Duration,
+    /// Whether controllers must number their events with a `seq` greater than the one of their
+    /// previous event, starting from 0, so a captured frame can't be replayed.
+    pub enforce_seq: bool,
+    /// Commands of a controller waiting, in a queue of its own, for room in the channel of its
+    /// player, where `None` rejects them as soon as the channel is full.
+    ///
+    /// Each queued command holds up to `max_message_size` bytes, so the queues can take up to
+    /// `controller_queue * max_message_size` bytes per controller, on top of the channels.
+    pub controller_queue: Option<usize>,
+    /// Interval between the `Ping` events sent to players and controllers, where zero disables
+    /// them.
+    ///
+    /// Browsers can't send WebSocket pings, so their connections look idle to proxies that don't
+    /// forward the pings of the server, which the events keep open.
+    pub app_ping_interval: Duration,
+    /// Time from the start of a player or controller session after which it's unpaired and closed,
+    /// so it has to register or pair again, where zero lets sessions last forever.
+    pub max_session_duration: Duration,
+    /// Policy applied to the `Pair` commands of controllers that are already paired.
+    pub pair_policy: PairPolicy,
+    /// Whether the HTTP responses are compressed, which hosts short on CPU may turn off.
+    pub compression_enabled: bool,
+    /// Proxies whose `Fly-Client-IP` and `X-Forwarded-For` headers are trusted to tell the client
+    /// IP, which the rate limit keys on. Requests from other peers are keyed on their own IP.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Whether controllers of devices that aren't registered can ask, with `echo=true`, for their
+    /// commands to be echoed back, for client developers to test against. Off by default, as
+    /// nothing is forwarded to a player in that mode.
+    pub echo_enabled: bool,
+}
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        return ConfigBuilder::default();
+    }
+}
+
+/// Shown in place of the secrets of the configuration.
+const REDACTED: &str = "***";
+
+/// Lists the values of the configuration for operators to check, with the admin token redacted
+/// and the audit sink left out.
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f
+            .debug_struct("Config")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("addresses", &self.addresses)
+            .field("origins", &self.origins)
+            .field("player_origins", &self.player_origins)
+            .field("controller_origins", &self.controller_origins)
+            .field("cors_methods", &self.cors_methods)
+            .field("cors_headers", &self.cors_headers)
+            .field("cors_credentials", &self.cors_credentials)
+            .field("body_limit", &self.body_limit)
+            .field("ping_interval", &self.ping_interval)
+            .field("ping_timeout", &self.ping_timeout)
+            .field("admin_token", &self.admin_token.as_ref().map(|_| REDACTED))
+            .field("drain_timeout", &self.drain_timeout)
+            .field("connections_per_minute", &self.connections_per_minute)
+            .field("reconnect_grace", &self.reconnect_grace)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_message_size", &self.max_message_size)
+            .field("channel_capacity", &self.channel_capacity)
+            .field("max_devices", &self.max_devices)
+            .field("request_id_header", &self.request_id_header)
+            .field("reap_interval", &self.reap_interval)
+            .field("tls_cert", &self.tls_cert)
+            .field("tls_key", &self.tls_key)
+            .field("write_timeout", &self.write_timeout)
+            .field("compression_level", &self.compression_level)
+            .field("compression_min_size", &self.compression_min_size)
+            .field("compression_content_types", &self.compression_content_types)
+            .field("id_format", &self.id_format)
+            .field("play_schemes", &self.play_schemes)
+            .field("access_log", &self.access_log.is_some())
+            .field("max_frame_size", &self.max_frame_size)
+            .field("max_ws_message_size", &self.max_ws_message_size)
+            .field("bind_retries", &self.bind_retries)
+            .field("bind_retry_delay", &self.bind_retry_delay)
+            .field("commands_per_second", &self.commands_per_second)
+            .field("handshake_timeout", &self.handshake_timeout)
+            .field("routes", &self.routes)
+            .field("ack_timeout", &self.ack_timeout)
+            .field("allowed_commands", &self.allowed_commands)
+            .field("backlog", &self.backlog)
+            .field("tcp_keepalive", &self.tcp_keepalive)
+            .field("enforce_seq", &self.enforce_seq)
+            .field("controller_queue", &self.controller_queue)
+            .field("app_ping_interval", &self.app_ping_interval)
+            .field("max_session_duration", &self.max_session_duration)
+            .field("pair_policy", &self.pair_policy)
+            .field("compression_enabled", &self.compression_enabled)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("echo_enabled", &self.echo_enabled)
+            .finish_non_exhaustive();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// The host is empty.
+    EmptyHost,
+    /// The port isn't a number between 0 and 65535.
+    InvalidPort(String),
+    /// The named value must be greater than zero.
+    Zero(&'static str),
+    /// The request id header isn't a valid header name.
+    InvalidHeader(String),
+    /// The route path doesn't start with a `/`.
+    InvalidRoute(String),
+    /// The CORS origin isn't a scheme and host, with an optional port.
+    InvalidOrigin(String),
+    /// The CORS method isn't a valid HTTP method.
+    InvalidMethod(String),
+    /// The allowed command isn't one controllers can send.
+    UnknownCommand(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            ConfigError::EmptyHost => write!(f, "host must not be empty"),
+            ConfigError::InvalidPort(port) => write!(f, "invalid port {:?}", port),
+            ConfigError::Zero(name) => write!(f, "{} must be greater than zero", name),
+            ConfigError::InvalidHeader(name) => write!(f, "invalid header name {:?}", name),
+            ConfigError::InvalidRoute(path) => write!(f, "invalid route path {:?}", path),
+            ConfigError::InvalidOrigin(origin) => {
+                write!(f, "invalid CORS origin {:?}, expected scheme://host[:port]", origin)
+            }
+            ConfigError::InvalidMethod(method) => write!(f, "invalid CORS method {:?}", method),
+            ConfigError::UnknownCommand(name) => write!(f, "unknown controller command {:?}", name),
+        };
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Parses the names of the commands controllers are allowed to send, as in `Play`, where no name
+/// allows every command.
+///
+/// Any name that isn't a controller command is an error rather than skipped, so a typo can't
+/// leave every command allowed.
+pub fn parse_commands(names: Vec<String>) -> Result<HashSet<Command>, ConfigError> {
+    if names.is_empty() {
+        return Ok(CONTROLLER_COMMANDS.iter().cloned().collect());
+    }
+
+    return names
+        .into_iter()
+        .map(|name| match serde_json::from_value(serde_json::Value::String(name.clone())) {
+            Ok(command) if CONTROLLER_COMMANDS.contains(&command) => Ok(command),
+            _ => Err(ConfigError::UnknownCommand(name)),
+        })
+        .collect();
+}
+
+/// Builds a [`Config`], validating the values that would otherwise fail deep in the server.
+pub struct ConfigBuilder {
+    host: String,
+    port: String,
+    addresses: Vec<SocketAddr>,
+    origins: Vec<String>,
+    player_origins: Vec<String>,
+    controller_origins: Vec<String>,
+    cors_methods: Vec<String>,
+    cors_headers: Vec<String>,
+    cors_credentials: bool,
+    body_limit: usize,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    admin_token: Option<String>,
+    drain_timeout: Duration,
+    connections_per_minute: u32,
+    reconnect_grace: Duration,
+    idle_timeout: Duration,
+    max_message_size: usize,
+    channel_capacity: usize,
+    max_devices: usize,
+    request_id_header: String,
+    reap_interval: Duration,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    audit: Arc<dyn AuditSink>,
+    write_timeout: Duration,
+    compression_level: Option<i32>,
+    compression_min_size: u16,
+    compression_content_types: Vec<String>,
+    id_format: IdFormat,
+    play_schemes: Vec<String>,
+    access_log: Option<Arc<AccessLog>>,
+    max_frame_size: usize,
+    max_ws_message_size: usize,
+    bind_retries: u32,
+    bind_retry_delay: Duration,
+    commands_per_second: u32,
+    handshake_timeout: Duration,
+    routes: RouteConfig,
+    ack_timeout: Duration,
+    allowed_commands: HashSet<Command>,
+    backlog: u32,
+    tcp_keepalive: Duration,
+    enforce_seq: bool,
+    controller_queue: Option<usize>,
+    app_ping_interval: Duration,
+    max_session_duration: Duration,
+    pair_policy: PairPolicy,
+    compression_enabled: bool,
+    trusted_proxies: Vec<IpAddr>,
+    echo_enabled: bool,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        Self {
+            host: DEFAULT_SERVER_HOST.to_string(),
+            port: DEFAULT_SERVER_PORT.to_string(),
+            addresses: Vec::new(),
+            origins: Vec::new(),
+            player_origins: Vec::new(),
+            controller_origins: Vec::new(),
+            cors_methods: vec![Method::GET.to_string()],
+            cors_headers: Vec::new(),
+            cors_credentials: false,
+            body_limit: DEFAULT_REQUEST_BODY_LIMIT,
+            ping_interval: Duration::from_secs(DEFAULT_PING_INTERVAL_SECS),
+            ping_timeout: Duration::from_secs(DEFAULT_PING_TIMEOUT_SECS),
+            admin_token: None,
+            drain_timeout: Duration::from_secs(DEFAULT_DRAIN_TIMEOUT_SECS),
+            connections_per_minute: DEFAULT_CONNECTIONS_PER_MINUTE,
+            reconnect_grace: Duration::from_secs(DEFAULT_RECONNECT_GRACE_SECS),
+            idle_timeout: Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            max_devices: DEFAULT_MAX_DEVICES,
+            request_id_header: DEFAULT_REQUEST_ID_HEADER.to_string(),
+            reap_interval: Duration::from_secs(DEFAULT_REAP_INTERVAL_SECS),
+            tls_cert: None,
+            tls_key: None,
+            audit: Arc::new(NoopAuditSink),
+            write_timeout: Duration::from_secs(DEFAULT_WRITE_TIMEOUT_SECS),
+            compression_level: None,
+            compression_min_size: DEFAULT_COMPRESSION_MIN_SIZE,
+            compression_content_types: Vec::new(),
+            id_format: IdFormat::default(),
+            play_schemes: DEFAULT_PLAY_SCHEMES.iter().map(|s| s.to_string()).collect(),
+            access_log: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_ws_message_size: DEFAULT_MAX_WS_MESSAGE_SIZE,
+            bind_retries: DEFAULT_BIND_RETRIES,
+            bind_retry_delay: Duration::from_millis(DEFAULT_BIND_RETRY_DELAY_MILLIS),
+            commands_per_second: DEFAULT_COMMANDS_PER_SECOND,
+            handshake_timeout: Duration::from_secs(DEFAULT_HANDSHAKE_TIMEOUT_SECS),
+            routes: RouteConfig::default(),
+            ack_timeout: Duration::from_secs(DEFAULT_ACK_TIMEOUT_SECS),
+            allowed_commands: CONTROLLER_COMMANDS.iter().cloned().collect(),
+            backlog: DEFAULT_BACKLOG,
+            tcp_keepalive: Duration::from_secs(DEFAULT_TCP_KEEPALIVE_SECS),
+            enforce_seq: false,
+            controller_queue: None,
+            app_ping_interval: Duration::ZERO,
+            max_session_duration: Duration::ZERO,
+            pair_policy: PairPolicy::default(),
+            compression_enabled: true,
+            trusted_proxies: Vec::new(),
+            echo_enabled: false,
+        }
+    }
+}
+
+impl ConfigBuilder {
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into();
+        self
+    }
+
+    pub fn port(mut self, port: impl Into<String>) -> Self {
+        self.port = port.into();
+        self
+    }
+
+    pub fn addresses(mut self, addresses: Vec<SocketAddr>) -> Self {
+        self.addresses = addresses;
+        self
+    }
+
+    pub fn origins(mut self, origins: Vec<String>) -> Self {
+        self.origins = origins;
+        self
+    }
+
+    pub fn player_origins(mut self, player_origins: Vec<String>) -> Self {
+        self.player_origins = player_origins;
+        self
+    }
+
+    pub fn controller_origins(mut self, controller_origins: Vec<String>) -> Self {
+        self.controller_origins = controller_origins;
+        self
+    }
+
+    pub fn cors_methods(mut self, cors_methods: Vec<String>) -> Self {
+        self.cors_methods = cors_methods;
+        self
+    }
+
+    pub fn cors_headers(mut self, cors_headers: Vec<String>) -> Self {
+        self.cors_headers = cors_headers;
+        self
+    }
+
+    pub fn cors_credentials(mut self, cors_credentials: bool) -> Self {
+        self.cors_credentials = cors_credentials;
+        self
+    }
+
+    pub fn body_limit(mut self, body_limit: usize) -> Self {
+        self.body_limit = body_limit;
+        self
+    }
+
+    pub fn ping_interval(mut self, ping_interval: Duration) -> Self {
+        self.ping_interval = ping_interval;
+        self
+    }
+
+    pub fn ping_timeout(mut self, ping_timeout: Duration) -> Self {
+        self.ping_timeout = ping_timeout;
+        self
+    }
+
+    pub fn admin_token(mut self, admin_token: Option<String>) -> Self {
+        self.admin_token = admin_token;
+        self
+    }
+
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    pub fn connections_per_minute(mut self, connections_per_minute: u32) -> Self {
+        self.connections_per_minute = connections_per_minute;
+        self
+    }
+
+    pub fn reconnect_grace(mut self, reconnect_grace: Duration) -> Self {
+        self.reconnect_grace = reconnect_grace;
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    pub fn channel_capacity(mut self, channel_capacity: usize) -> Self {
+        self.channel_capacity = channel_capacity;
+        self
+    }
+
+    pub fn max_devices(mut self, max_devices: usize) -> Self {
+        self.max_devices = max_devices;
+        self
+    }
+
+    pub fn request_id_header(mut self, request_id_header: impl Into<String>) -> Self {
+        self.request_id_header = request_id_header.into();
+        self
+    }
+
+    pub fn reap_interval(mut self, reap_interval: Duration) -> Self {
+        self.reap_interval = reap_interval;
+        self
+    }
+
+    pub fn tls_cert(mut self, tls_cert: Option<PathBuf>) -> Self {
+        self.tls_cert = tls_cert;
+        self
+    }
+
+    pub fn tls_key(mut self, tls_key: Option<PathBuf>) -> Self {
+        self.tls_key = tls_key;
+        self
+    }
+
+    pub fn audit(mut self, audit: Arc<dyn AuditSink>) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    pub fn write_timeout(mut self, write_timeout: Duration) -> Self {
+        self.write_timeout = write_timeout;
+        self
+    }
+
+    pub fn compression_level(mut self, compression_level: Option<i32>) -> Self {
+        self.compression_level = compression_level;
+        self
+    }
+
+    pub fn compression_min_size(mut self, compression_min_size: u16) -> Self {
+        self.compression_min_size = compression_min_size;
+        self
+    }
+
+    pub fn compression_content_types(mut self, compression_content_types: Vec<String>) -> Self {
+        self.compression_content_types = compression_content_types;
+        self
+    }
+
+    pub fn id_format(mut self, id_format: IdFormat) -> Self {
+        self.id_format = id_format;
+        self
+    }
+
+    pub fn play_schemes(mut self, play_schemes: Vec<String>) -> Self {
+        self.play_schemes = play_schemes;
+        self
+    }
+
+    pub fn access_log(mut self, access_log: Option<Arc<AccessLog>>) -> Self {
+        self.access_log = access_log;
+        self
+    }
+
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn max_ws_message_size(mut self, max_ws_message_size: usize) -> Self {
+        self.max_ws_message_size = max_ws_message_size;
+        self
+    }
+
+    pub fn bind_retries(mut self, bind_retries: u32) -> Self {
+        self.bind_retries = bind_retries;
+        self
+    }
+
+    pub fn bind_retry_delay(mut self, bind_retry_delay: Duration) -> Self {
+        self.bind_retry_delay = bind_retry_delay;
+        self
+    }
+
+    pub fn commands_per_second(mut self, commands_per_second: u32) -> Self {
+        self.commands_per_second = commands_per_second;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub fn routes(mut self, routes: RouteConfig) -> Self {
+        self.routes = routes;
+        self
+    }
+
+    pub fn ack_timeout(mut self, ack_timeout: Duration) -> Self {
+        self.ack_timeout = ack_timeout;
+        self
+    }
+
+    pub fn allowed_commands(mut self, allowed_commands: HashSet<Command>) -> Self {
+        self.allowed_commands = allowed_commands;
+        self
+    }
+
+    pub fn backlog(mut self, backlog: u32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    pub fn tcp_keepalive(mut self, tcp_keepalive: Duration) -> Self {
+        self.tcp_keepalive = tcp_keepalive;
+        self
+    }
+
+    pub fn enforce_seq(mut self, enforce_seq: bool) -> Self {
+        self.enforce_seq = enforce_seq;
+        self
+    }
+
+    pub fn controller_queue(mut self, controller_queue: Option<usize>) -> Self {
+        self.controller_queue = controller_queue;
+        self
+    }
+
+    pub fn app_ping_interval(mut self, app_ping_interval: Duration) -> Self {
+        self.app_ping_interval = app_ping_interval;
+        self
+    }
+
+    pub fn max_session_duration(mut self, max_session_duration: Duration) -> Self {
+        self.max_session_duration = max_session_duration;
+        self
+    }
+
+    pub fn pair_policy(mut self, pair_policy: PairPolicy) -> Self {
+        self.pair_policy = pair_policy;
+        self
+    }
+
+    pub fn compression_enabled(mut self, compression_enabled: bool) -> Self {
+        self.compression_enabled = compression_enabled;
+        self
+    }
+
+    pub fn trusted_proxies(mut self, trusted_proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    pub fn echo_enabled(mut self, echo_enabled: bool) -> Self {
+        self.echo_enabled = echo_enabled;
+        self
+    }
+
+    pub fn build(self) -> Result<Config, ConfigError> {
+        let host = self.host.trim().to_string();
+
+        if host.is_empty() {
+            return Err(ConfigError::EmptyHost);
+        }
+
+        let port = match self.port.trim().parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => return Err(ConfigError::InvalidPort(self.port)),
+        };
+
+        if self.channel_capacity == 0 {
+            return Err(ConfigError::Zero("channel capacity"));
+        }
+
+        if self.controller_queue == Some(0) {
+            return Err(ConfigError::Zero("controller queue"));
+        }
+
+        if self.write_timeout.is_zero() {
+            return Err(ConfigError::Zero("write timeout"));
+        }
+
+        if self.ping_interval.is_zero() {
+            return Err(ConfigError::Zero("ping interval"));
+        }
+
+        if self.idle_timeout.is_zero() {
+            return Err(ConfigError::Zero("idle timeout"));
+        }
+
+        if self.max_devices == 0 {
+            return Err(ConfigError::Zero("max devices"));
+        }
+
+        if self.max_frame_size == 0 {
+            return Err(ConfigError::Zero("max frame size"));
+        }
+
+        if self.max_ws_message_size == 0 {
+            return Err(ConfigError::Zero("max WebSocket message size"));
+        }
+
+        let request_id_header = match HeaderName::try_from(self.request_id_header.trim()) {
+            Ok(name) => name,
+            Err(_) => return Err(ConfigError::InvalidHeader(self.request_id_header)),
+        };
+
+        if let Some(path) = self.routes.invalid() {
+            return Err(ConfigError::InvalidRoute(path.to_string()));
+        }
+
+        // Checked here rather than when the CORS layers are built, so the server doesn't start
+        // without the origins it was meant to allow.
+        if let Some(origin) = [&self.origins, &self.player_origins, &self.controller_origins]
+            .into_iter()
+            .flatten()
+            .find(|origin| !valid_origin(origin))
+        {
+            return Err(ConfigError::InvalidOrigin(origin.clone()));
+        }
+
+        let mut cors_methods = Vec::with_capacity(self.cors_methods.len());
+
+        for method in &self.cors_methods {
+            match Method::from_str(&method.trim().to_uppercase()) {
+                Ok(parsed) => cors_methods.push(parsed),
+                Err(_) => return Err(ConfigError::InvalidMethod(method.clone())),
+            }
+        }
+
+        let mut cors_headers = Vec::with_capacity(self.cors_headers.len());
+
+        for header in &self.cors_headers {
+            match HeaderName::try_from(header.trim()) {
+                Ok(name) => cors_headers.push(name),
+                Err(_) => return Err(ConfigError::InvalidHeader(header.clone())),
+            }
+        }
+
+        return Ok(Config {
+            host,
+            port,
+            addresses: self.addresses,
+            origins: self.origins,
+            player_origins: self.player_origins,
+            controller_origins: self.controller_origins,
+            cors_methods,
+            cors_headers,
+            cors_credentials: self.cors_credentials,
+            body_limit: self.body_limit,
+            ping_interval: self.ping_interval,
+            ping_timeout: self.ping_timeout,
+            admin_token: self.admin_token,
+            drain_timeout: self.drain_timeout,
+            connections_per_minute: self.connections_per_minute,
+            reconnect_grace: self.reconnect_grace,
+            idle_timeout: self.idle_timeout,
+            max_message_size: self.max_message_size,
+            channel_capacity: self.channel_capacity,
+            max_devices: self.max_devices,
+            request_id_header,
+            reap_interval: self.reap_interval,
+            tls_cert: self.tls_cert,
+            tls_key: self.tls_key,
+            audit: self.audit,
+            write_timeout: self.write_timeout,
+            compression_level: self.compression_level,
+            compression_min_size: self.compression_min_size,
+            compression_content_types: self.compression_content_types,
+            id_format: self.id_format,
+            play_schemes: self.play_schemes,
+            access_log: self.access_log,
+            max_frame_size: self.max_frame_size,
+            max_ws_message_size: self.max_ws_message_size,
+            bind_retries: self.bind_retries,
+            bind_retry_delay: self.bind_retry_delay,
+            commands_per_second: self.commands_per_second,
+            handshake_timeout: self.handshake_timeout,
+            routes: self.routes,
+            ack_timeout: self.ack_timeout,
+            allowed_commands: self.allowed_commands,
+            backlog: self.backlog,
+            tcp_keepalive: self.tcp_keepalive,
+            enforce_seq: self.enforce_seq,
+            controller_queue: self.controller_queue,
+            app_ping_interval: self.app_ping_interval,
+            max_session_duration: self.max_session_duration,
+            pair_policy: self.pair_policy,
+            compression_enabled: self.compression_enabled,
+            trusted_proxies: self.trusted_proxies,
+            echo_enabled: self.echo_enabled,
+        });
+    }
+}
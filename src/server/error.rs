@@ -0,0 +1,96 @@
+use std::{io, net::SocketAddr};
+
+use axum::{
+    Json,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::server::config::ConfigError;
+
+/// Failure to start or run the server.
+#[derive(Debug, thiserror::Error)]
+pub enum ServerError {
+    #[error("failed to bind {addr}: {source}")]
+    Bind {
+        addr: SocketAddr,
+        #[source]
+        source: io::Error,
+    },
+    #[error("invalid configuration: {0}")]
+    Config(#[from] ConfigError),
+    #[error("failed to set up TLS: {0}")]
+    Tls(#[source] io::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, ServerError>;
+
+/// Codes identifying the errors returned by the HTTP routes.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The controller didn't give the device to control.
+    MissingDevice,
+    /// The controller didn't give the secret of the device.
+    MissingSecret,
+    /// The request lacks a valid admin token.
+    Unauthorized,
+    /// The client made too many requests.
+    RateLimited,
+    /// The client only speaks WebSocket subprotocols the server doesn't.
+    UnsupportedProtocol,
+    /// No player is registered with the device.
+    DeviceNotFound,
+    /// The last will of the player isn't a valid event.
+    InvalidWill,
+    /// The player didn't take a command within the write timeout.
+    PlayerUnreachable,
+    /// The pairing code requested by the player isn't a short device id.
+    InvalidCode,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        return match self {
+            ErrorCode::MissingDevice
+            | ErrorCode::MissingSecret
+            | ErrorCode::UnsupportedProtocol
+            | ErrorCode::InvalidWill
+            | ErrorCode::InvalidCode => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::DeviceNotFound => StatusCode::NOT_FOUND,
+            ErrorCode::PlayerUnreachable => StatusCode::SERVICE_UNAVAILABLE,
+        };
+    }
+}
+
+/// Error returned by the HTTP routes, serialized as `{ "error": { "code": ..., "message": ... } }`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: ApiError,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        return (self.code.status(), Json(ErrorBody { error: self })).into_response();
+    }
+}
@@ -1,89 +1,527 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    fmt, future, io,
+    net::SocketAddr,
+    string::FromUtf8Error,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 use tokio::{
     select,
-    sync::{RwLock, mpsc},
+    sync::{
+        broadcast,
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+    time::{self, Instant, MissedTickBehavior},
 };
 
+use futures::{Sink, SinkExt, future::join_all};
 use serde::{Deserialize, Serialize};
-use tracing::{Value, debug, error, info, trace, warn};
+use subtle::ConstantTimeEq;
+use tracing::{Instrument, debug, error, info, info_span, trace, warn};
+use url::Url;
 
 use axum::{
+    Json,
+    body::Bytes,
     extract::{
-        Extension, Query,
+        ConnectInfo, Extension, Path, Query,
         ws::{Message, Utf8Bytes, WebSocket, WebSocketUpgrade},
     },
-    response::IntoResponse,
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+
+use crate::server::{
+    Config,
+    audit::AuditRecord,
+    config::{IdFormat, PairPolicy},
+    error::{ApiError, ErrorCode},
+    limit::TokenBucket,
+    metrics,
+    observer::Peer,
+    protocol::{
+        Action, CloseReason, Command, Event, ProtocolError, SCHEMA_VERSION, SUBPROTOCOL,
+        ServerInfo, parse_message,
+    },
+    rpc::{self, Framing, JSONRPC_SUBPROTOCOL},
+    secret::{Secret, exposed},
+    state::{DeviceGuard, Handoff, RegisterError, State, StateEvent},
+    state_machine::ControllerState,
 };
 
-use crate::server::state::{Channel, State};
+/// Bytes of a message from a controller kept in the trace logs, for big messages not to flood them.
+const TRACED_MESSAGE_BYTES: usize = 256;
 
 /// Payload for the register and unregister a new player.
 #[derive(Serialize, Deserialize)]
-struct Registration {
+pub struct Registration {
     /// Device name.
-    device: String,
-    /// Device secret.
-    secret: String,
+    pub device: String,
+    /// Device secret, the only place it is serialized.
+    #[serde(with = "exposed")]
+    pub secret: Secret,
+    /// Token to resume the device after a disconnection.
+    pub token: String,
+    /// Event delivered to the controllers of the device when the player disconnects, as given by
+    /// the player in the `will` query parameter.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub will: Option<Event>,
+}
+
+/// Failure to deliver the registration to a player.
+#[derive(Debug)]
+pub enum RegistrationError {
+    /// The registration couldn't be serialized.
+    Serialize(serde_json::Error),
+    /// The player didn't take the registration.
+    Send(String),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-pub enum Command {
-    Pair,
-    Unpair,
-    Play,
-    Stop,
+impl fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            RegistrationError::Serialize(e) => write!(f, "failed to serialize registration: {}", e),
+            RegistrationError::Send(e) => write!(f, "failed to send registration: {}", e),
+        };
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Event {
-    pub command: Command,
-    pub payload: Option<String>,
+impl std::error::Error for RegistrationError {}
+
+/// Sends the registration to the player, failing when it can't be serialized or isn't taken
+/// within the timeout.
+pub async fn send_registration<S>(
+    sink: &mut S,
+    registration: &Registration,
+    timeout: Duration,
+) -> Result<(), RegistrationError>
+where
+    S: Sink<Message> + Unpin,
+    S::Error: fmt::Display,
+{
+    let msg = serde_json::to_string(registration).map_err(RegistrationError::Serialize)?;
+
+    return match time::timeout(timeout, sink.send(Message::text(msg))).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(RegistrationError::Send(e.to_string())),
+        Err(_) => Err(RegistrationError::Send("timed out".to_string())),
+    };
 }
 
-impl ToString for Event {
-    fn to_string(&self) -> String {
-        return format!(
-            "command {:?} with payload of {:?}",
-            self.command, self.payload
-        );
+/// Sends the message, failing when the peer doesn't take it within the timeout so a stuck
+/// connection can't hold its session forever.
+async fn send(socket: &mut WebSocket, msg: Message, timeout: Duration) -> Result<(), axum::Error> {
+    return match time::timeout(timeout, socket.send(msg)).await {
+        Ok(result) => result,
+        Err(_) => Err(axum::Error::new(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "websocket write timed out",
+        ))),
+    };
+}
+
+/// Sends the message to a controller, turning its text into the framing the controller speaks.
+async fn send_framed(
+    socket: &mut WebSocket,
+    framing: Framing,
+    msg: Message,
+    timeout: Duration,
+) -> Result<(), axum::Error> {
+    let msg = match (framing, msg) {
+        (Framing::JsonRpc, Message::Text(text)) => Message::text(rpc::to_rpc(text.as_str())),
+        (_, msg) => msg,
+    };
+
+    return send(socket, msg, timeout).await;
+}
+
+/// Builds the message describing the server, sent first on every connection.
+fn server_info() -> Message {
+    return Message::text(serde_json::to_string(&ServerInfo::new()).unwrap());
+}
+
+/// Builds the `Ping` event keeping the connections of browsers open.
+fn app_ping() -> Message {
+    return Message::text(serde_json::to_string(&Event::new(Action::Ping)).unwrap());
+}
+
+/// Reads the `User-Agent` header, defaulting to an empty string.
+fn user_agent(headers: &HeaderMap) -> String {
+    return headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+}
+
+/// Negotiates one of the subprotocols, failing when the client only asks for ones the route
+/// doesn't speak.
+///
+/// Clients that don't ask for any subprotocol are accepted and assumed to speak the current one.
+fn negotiate(
+    ws: WebSocketUpgrade,
+    headers: &HeaderMap,
+    protocols: &[&'static str],
+) -> Result<WebSocketUpgrade, ApiError> {
+    let ws = ws.protocols(protocols.iter().copied());
+
+    if headers.contains_key(header::SEC_WEBSOCKET_PROTOCOL) && ws.selected_protocol().is_none() {
+        return Err(ApiError::new(
+            ErrorCode::UnsupportedProtocol,
+            format!("unsupported subprotocol, expected {}", protocols.join(" or ")),
+        ));
     }
+
+    return Ok(ws);
 }
 
 pub async fn player(
     ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Extension(state): Extension<Arc<State>>,
-) -> impl IntoResponse {
-    info!("player route called");
+    Extension(config): Extension<Arc<Config>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let span = info_span!("player", ip = %addr.ip(), user_agent = user_agent(&headers));
+
+    span.in_scope(|| info!("player route called"));
+
+    let will = match params.get("will").map(|will| serde_json::from_str::<Event>(will)) {
+        Some(Ok(will)) => Some(will),
+        Some(Err(e)) => {
+            span.in_scope(|| error!(error = e.to_string(), "invalid last will"));
+
+            return ApiError::new(ErrorCode::InvalidWill, "invalid last will").into_response();
+        }
+        None => None,
+    };
+
+    // Checked before the upgrade, as the registration would refuse it anyway.
+    if let Some(code) = params.get("code")
+        && !IdFormat::is_short(code)
+    {
+        span.in_scope(|| error!(code = code, "invalid pairing code"));
+
+        return ApiError::new(ErrorCode::InvalidCode, "invalid pairing code").into_response();
+    }
+
+    let ws = match negotiate(ws, &headers, &[SUBPROTOCOL]) {
+        Ok(ws) => ws,
+        Err(e) => {
+            span.in_scope(|| error!("player requested an unsupported subprotocol"));
+
+            return e.into_response();
+        }
+    };
 
-    ws.on_upgrade(move |socket| handle_player(socket, state))
+    return ws
+        .max_frame_size(config.max_frame_size)
+        .max_message_size(config.max_ws_message_size)
+        .on_upgrade(move |socket| {
+            handle_player(socket, state, config, params, will).instrument(span)
+        })
+        .into_response();
 }
 
-async fn handle_player(mut socket: WebSocket, state: Arc<State>) {
+/// Registers a new device, under the pairing code requested by the player if any, returning its
+/// registration, the receiver for its commands and the sender for its replies.
+async fn register(
+    state: &State,
+    config: &Config,
+    code: Option<&str>,
+) -> Result<
+    (
+        Registration,
+        mpsc::Receiver<Event>,
+        broadcast::Sender<Utf8Bytes>,
+    ),
+    CloseReason,
+> {
     debug!("registering device");
 
-    let device = uuid::Uuid::new_v4().to_string();
-    let secret = "".to_string();
+    let (capacity, max_devices) = (config.channel_capacity, config.max_devices);
 
-    info!(device = device, "device registered");
+    let registered = match code {
+        Some(code) => state.register_device_as(capacity, max_devices, code.to_string()).await,
+        None => state
+            .register_device(capacity, max_devices, config.id_format)
+            .await
+            .ok_or(RegisterError::Full),
+    };
 
-    let (sx, mut rx) = mpsc::channel(100);
+    let registered = match registered {
+        Ok(registered) => registered,
+        Err(RegisterError::Full) => {
+            warn!("maximum number of devices reached");
 
-    let mut channels = state.channels.write().await;
-    channels.insert(device.clone(), RwLock::new(Channel { sender: Some(sx) }));
-    drop(channels);
+            return Err(CloseReason::TooManyDevices);
+        }
+        Err(RegisterError::Taken) => {
+            warn!(code = code, "requested pairing code is taken");
+
+            return Err(CloseReason::CodeTaken);
+        }
+        Err(RegisterError::InvalidCode) => {
+            error!(code = code, "invalid pairing code");
+
+            return Err(CloseReason::ProtocolError);
+        }
+    };
+
+    info!(device = registered.device, "device registered");
 
     let registration = Registration {
-        device: device.clone(),
-        secret,
+        device: registered.device,
+        secret: registered.secret,
+        token: registered.token,
+        will: None,
+    };
+
+    return Ok((registration, registered.receiver, registered.replies));
+}
+
+/// Resumes the disconnected device matching the token, returning its registration, the receiver for
+/// its commands and the sender for its replies.
+async fn resume(
+    state: &State,
+    token: &str,
+) -> Option<(
+    Registration,
+    mpsc::Receiver<Event>,
+    broadcast::Sender<Utf8Bytes>,
+)> {
+    let channels = state.channels.read().await;
+
+    for (device, channel) in channels.iter() {
+        let mut lock = channel.write().await;
+
+        if lock.token != token {
+            continue;
+        }
+
+        let (_, rx) = lock.parked.take()?;
+
+        lock.connected_at = SystemTime::now();
+
+        info!(device = device, "device resumed");
+
+        let registration = Registration {
+            device: device.clone(),
+            secret: lock.secret.clone(),
+            token: lock.token.clone(),
+            will: None,
+        };
+
+        return Some((registration, rx, lock.replies.clone()));
+    }
+
+    return None;
+}
+
+/// Parks the receiver of a disconnected player, removing the device if the player doesn't
+/// reconnect within the grace period.
+///
+/// Nothing is parked when the device of the token was removed, even if another device was
+/// registered under its name since.
+async fn park(
+    state: Arc<State>,
+    device: String,
+    token: String,
+    rx: mpsc::Receiver<Event>,
+    grace: Duration,
+) {
+    let channels = state.channels.read().await;
+
+    let parked = match channels.get(&device) {
+        Some(channel) => {
+            let mut lock = channel.write().await;
+
+            if lock.token == token {
+                lock.parked = Some((Instant::now(), rx));
+            }
+
+            lock.token == token
+        }
+        None => false,
+    };
+
+    drop(channels);
+
+    if !parked {
+        debug!("device already unregistered, not waiting for reconnection");
+
+        return;
+    }
+
+    info!("device waiting for reconnection");
+
+    let expiry = async move {
+        time::sleep(grace).await;
+
+        let mut channels = state.channels.write().await;
+
+        let expired = match channels.get(&device) {
+            Some(channel) => {
+                let lock = channel.read().await;
+
+                match &lock.parked {
+                    Some((since, _)) => lock.token == token && since.elapsed() >= grace,
+                    None => false,
+                }
+            }
+            None => false,
+        };
+
+        if expired {
+            channels.remove(&device);
+            drop(channels);
+
+            state.auth.revoke(&device).await;
+
+            state.publish(StateEvent::DeviceRemoved {
+                device: device.clone(),
+            });
+
+            info!("device unregistered after reconnection window expired");
+        }
+    };
+
+    tokio::spawn(expiry.in_current_span());
+}
+
+/// Relays a reply from the player to the controllers attached to its device.
+fn relay(replies: &broadcast::Sender<Utf8Bytes>, text: Utf8Bytes) {
+    let event = match parse_message(&text) {
+        Ok(envelope) => envelope.event,
+        Err(e) => {
+            warn!(error = e.to_string(), "failed to parse event from player");
+
+            return;
+        }
+    };
+
+    match event.command() {
+        Command::Capabilities | Command::Heartbeat | Command::Ack | Command::Nack => {
+            if let Err(_) = replies.send(text) {
+                debug!("no controller to relay the player reply to");
+            }
+        }
+        _ => {
+            debug!("command from player ignored: {:?}", event.command());
+        }
+    }
+}
+
+async fn handle_player(
+    mut socket: WebSocket,
+    state: Arc<State>,
+    config: Arc<Config>,
+    params: HashMap<String, String>,
+    will: Option<Event>,
+) {
+    let timeout = config.write_timeout;
+
+    let resumed = match params.get("reconnect") {
+        Some(token) => {
+            let resumed = resume(&state, token).await;
+
+            if resumed.is_none() {
+                warn!("no device waiting for reconnection with the given token");
+            }
+
+            resumed
+        }
+        None => None,
+    };
+
+    let registered = match resumed {
+        Some(resumed) => Ok(resumed),
+        None => register(&state, &config, params.get("code").map(String::as_str)).await,
+    };
+
+    let (mut registration, rx, replies) = match registered {
+        Ok(registered) => registered,
+        Err(reason) => {
+            send_close(&mut socket, reason, timeout).await;
+
+            return;
+        }
+    };
+
+    registration.will = will;
+
+    // Every log of the session carries the device, to follow it among the concurrent sessions.
+    let span = info_span!("session", device = %registration.device);
+
+    serve_player(socket, state, config, registration, rx, replies)
+        .instrument(span)
+        .await;
+}
+
+/// Runs the session of a registered player until it disconnects.
+async fn serve_player(
+    mut socket: WebSocket,
+    state: Arc<State>,
+    config: Arc<Config>,
+    registration: Registration,
+    mut rx: mpsc::Receiver<Event>,
+    replies: broadcast::Sender<Utf8Bytes>,
+) {
+    let timeout = config.write_timeout;
+    let device = registration.device.clone();
+
+    // Removes the device if the task of the session is aborted before cleaning up.
+    let guard = DeviceGuard::new(state.clone(), device.clone(), registration.token.clone());
+
+    let expires_at = Instant::now() + config.max_session_duration;
+
+    // Subscribed before the registration is sent, so every registered player is told to stop.
+    let mut stop = state.stop.subscribe();
+
+    // The server info comes first so players can branch on the version before registering.
+    let sent = match send(&mut socket, server_info(), timeout).await {
+        Ok(_) => send_registration(&mut socket, &registration, timeout).await,
+        Err(e) => Err(RegistrationError::Send(e.to_string())),
     };
-    let msg = serde_json::to_string(&registration).unwrap();
 
-    if let Err(_) = socket.send(Message::text(msg.clone())).await {
-        error!("failed to send the registration message on websocket connection");
+    if let Err(e) = sent {
+        error!(error = e.to_string(), "failed to send the registration message");
+
+        if let RegistrationError::Serialize(_) = e {
+            send_close(&mut socket, CloseReason::InternalError, timeout).await;
+        }
+
+        // A player that never got its registration can't use or resume the device, which is
+        // removed here rather than by the guard.
+        rx.close();
+
+        guard.disarm();
+
+        state.unregister_session(&device, &registration.token).await;
 
         return;
     };
 
+    metrics::player_connected();
+
+    state.observer.on_player_connected(&device);
+
+    let mut ping = time::interval(config.ping_interval);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut last_seen = Instant::now();
+
+    let mut next_app_ping = Instant::now() + config.app_ping_interval;
+
+    // The device is kept for the player to reconnect unless the session ended on purpose.
+    let mut reconnectable = true;
+
+    // Reason sent along with the close frame ending the session, if any.
+    let mut close_reason: Option<CloseReason> = None;
+
     loop {
         select! {
             val = socket.recv() => {
@@ -97,7 +535,14 @@ async fn handle_player(mut socket: WebSocket, state: Arc<State>) {
 
                                 break;
                             },
-                            _ => {}
+                            Ok(Message::Text(text)) => {
+                                last_seen = Instant::now();
+
+                                relay(&replies, text);
+                            }
+                            _ => {
+                                last_seen = Instant::now();
+                            }
                         }
                     },
                     None => {
@@ -109,22 +554,20 @@ async fn handle_player(mut socket: WebSocket, state: Arc<State>) {
             }
             val = rx.recv() => {
                 match val {
-                    Some(msg) => {
-                        let event: Event = match serde_json::from_str(&msg.to_string()) {
-                            Ok(event) => event,
-                            Err(e) => {
-                                error!(error = e.to_string(), "failed to parse event");
-                                continue;
-                            }
-                        };
-
+                    Some(event) => {
                         debug!(event = event.to_string(), "received event on player side");
 
-                        match event.command {
-                            Command::Unpair => {
+                        // Events are serialized from their typed value, so only valid ones reach
+                        // the player.
+                        let msg = Message::text(serde_json::to_string(&event).unwrap());
+
+                        match event.action {
+                            Action::Unpair => {
                                 info!("player unpaired");
 
-                                if let Err(_) = socket.send(Message::text(msg.clone())).await {
+                                reconnectable = false;
+
+                                if let Err(_) = send(&mut socket, msg, timeout).await {
                                     error!("failed to send unpair message");
 
                                     break;
@@ -132,10 +575,31 @@ async fn handle_player(mut socket: WebSocket, state: Arc<State>) {
 
                                 break;
                             }
+                            Action::Reset => {
+                                info!("player released by its controller");
+
+                                // The player is unpaired but keeps its session, for a controller
+                                // to pair again.
+                                let unpair = Message::text(
+                                    serde_json::to_string(&Event {
+                                        action: Action::Unpair,
+                                        id: event.id,
+                                        schema_version: SCHEMA_VERSION,
+                                        seq: None,
+                                    })
+                                    .unwrap(),
+                                );
+
+                                if let Err(_) = send(&mut socket, unpair, timeout).await {
+                                    error!("failed to send unpair message");
+
+                                    break;
+                                };
+                            }
                             _ => {
-                                info!("command received on player side: {:?}", event.command);
+                                info!("command received on player side: {:?}", event.command());
 
-                                if let Err(_) = socket.send(Message::text(msg.clone())).await {
+                                if let Err(_) = send(&mut socket, msg, timeout).await {
                                     error!("failed to send message from player to client");
 
                                     break;
@@ -151,12 +615,62 @@ async fn handle_player(mut socket: WebSocket, state: Arc<State>) {
                     },
                 };
             }
+            _ = stop.recv() => {
+                info!("player session stopped by the server");
+
+                reconnectable = false;
+
+                break;
+            }
+            _ = time::sleep_until(expires_at), if !config.max_session_duration.is_zero() => {
+                info!("player session expired");
+
+                // The device goes away with its token, so the player has to register again.
+                reconnectable = false;
+                close_reason = Some(CloseReason::SessionExpired);
+
+                let unpair = Message::text(serde_json::to_string(&unpair_event()).unwrap());
+
+                if let Err(_) = send(&mut socket, unpair, timeout).await {
+                    error!("failed to send unpair message");
+                };
+
+                break;
+            }
+            _ = ping.tick() => {
+                if last_seen.elapsed() > config.ping_timeout {
+                    warn!("player didn't answer pings in time");
+
+                    break;
+                }
+
+                trace!("sending ping to player");
+
+                if let Err(_) = send(&mut socket, Message::Ping(Bytes::new()), timeout).await {
+                    error!("failed to send ping to player");
+
+                    break;
+                };
+            }
+            _ = time::sleep_until(next_app_ping), if !config.app_ping_interval.is_zero() => {
+                next_app_ping = Instant::now() + config.app_ping_interval;
+
+                trace!("sending ping event to player");
+
+                if let Err(e) = send(&mut socket, app_ping(), timeout).await {
+                    error!(error = e.to_string(), "failed to send ping event to player");
+
+                    break;
+                }
+            }
         };
     }
 
     trace!("player websocket loop exit");
 
-    match socket.send(Message::Close(None)).await {
+    let close = Message::Close(close_reason.map(|reason| reason.frame()));
+
+    match send(&mut socket, close, timeout).await {
         Ok(_) => {
             debug!("websocket close message send from player to client");
         }
@@ -168,286 +682,1202 @@ async fn handle_player(mut socket: WebSocket, state: Arc<State>) {
         }
     }
 
-    rx.close();
+    // Lets the controllers know the player is gone instead of leaving them in a stale state.
+    if let Some(will) = &registration.will {
+        let text = Utf8Bytes::from(serde_json::to_string(will).unwrap());
+
+        match replies.send(text) {
+            Ok(_) => info!("last will delivered to the controllers"),
+            Err(_) => debug!("no controller to deliver the last will to"),
+        }
+    }
+
+    state.observer.on_disconnected(&device, Peer::Player);
+
+    guard.disarm();
+
+    if reconnectable && !config.reconnect_grace.is_zero() {
+        park(
+            state.clone(),
+            device,
+            registration.token.clone(),
+            rx,
+            config.reconnect_grace,
+        )
+        .await;
+    } else {
+        rx.close();
 
-    trace!("trying to delete the devcie from channels");
+        trace!("trying to delete the devcie from channels");
 
-    let mut channels = state.channels.write().await;
-    channels.remove(&device);
+        // Removed by its token, since the name may belong to a new device by now.
+        if state.unregister_session(&device, &registration.token).await {
+            info!("device unregistered");
+        } else {
+            debug!("device already unregistered");
+        }
+    }
 
-    info!(device = device, "device unregistered");
+    metrics::player_disconnected();
 
     info!("webSocket connection closed on player side");
 }
 
 pub async fn controller(
     ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Extension(state): Extension<Arc<State>>,
+    Extension(config): Extension<Arc<Config>>,
     Query(params): Query<HashMap<String, String>>,
-) -> impl IntoResponse {
-    return ws.on_upgrade(move |socket| handle_controller(socket, state, params));
-}
-
-#[derive(Debug, Clone, Copy)]
-enum ControllerState {
-    Unpaired,
-    Paired,
-    Played,
-    Stopped,
-}
-
-impl Default for ControllerState {
-    fn default() -> Self {
-        ControllerState::Unpaired
-    }
-}
-
-impl ControllerState {
-    fn pair(&mut self) -> bool {
-        match *self {
-            ControllerState::Unpaired => {
-                *self = ControllerState::Paired;
-                true
-            }
-            _ => {
-                *self = ControllerState::Unpaired;
-                false
-            }
-        }
-    }
-
-    fn play(&mut self) -> bool {
-        match *self {
-            ControllerState::Paired | ControllerState::Stopped => {
-                *self = ControllerState::Played;
-                true
-            }
-            _ => {
-                *self = ControllerState::Unpaired;
-                false
-            }
-        }
-    }
-
-    fn stop(&mut self) -> bool {
-        match *self {
-            ControllerState::Played => {
-                *self = ControllerState::Stopped;
-                true
-            }
-            _ => {
-                *self = ControllerState::Unpaired;
-                false
-            }
-        }
-    }
+) -> Response {
+    let span = info_span!("controller", ip = %addr.ip(), user_agent = user_agent(&headers));
 
-    fn unpair(&mut self) -> bool {
-        match *self {
-            ControllerState::Paired | ControllerState::Played | ControllerState::Stopped => {
-                *self = ControllerState::Unpaired;
-                true
-            }
-            _ => {
-                *self = ControllerState::Unpaired;
-                false
-            }
-        }
-    }
-}
+    span.in_scope(|| info!("controller route called"));
 
-async fn handle_controller(
-    mut socket: WebSocket,
-    state: Arc<State>,
-    params: HashMap<String, String>,
-) {
     let device = match params.get("device") {
         Some(device) => device.clone(),
         None => {
-            error!("no device found in params");
+            span.in_scope(|| error!("no device found in params"));
 
-            return;
+            return ApiError::new(ErrorCode::MissingDevice, "missing device").into_response();
         }
     };
 
-    let _secret = match params.get("secret") {
-        Some(secret) => secret.clone(),
-        None => {
-            error!("no secret found in params");
+    // A controller taking over a device through a handoff presents its token instead of the secret.
+    let credential = match (params.get("handoff"), params.get("secret")) {
+        (Some(token), _) => Credential::Handoff(Secret::from(token.as_str())),
+        (None, Some(secret)) => Credential::Secret(Secret::from(secret.as_str())),
+        (None, None) => {
+            span.in_scope(|| error!("no secret found in params"));
 
-            return;
+            return ApiError::new(ErrorCode::MissingSecret, "missing secret").into_response();
         }
     };
 
-    let channels = state.channels.read().await;
-    let device = device;
+    let resume = params.get("resume").is_some_and(|resume| resume == "true");
+    let echo = params.get("echo").is_some_and(|echo| echo == "true");
 
-    let channel = match channels.get(&device) {
-        Some(tx) => tx,
-        None => {
-            error!("no channel found for device: {}", device);
+    let ws = match negotiate(ws, &headers, &[SUBPROTOCOL, JSONRPC_SUBPROTOCOL]) {
+        Ok(ws) => ws,
+        Err(e) => {
+            span.in_scope(|| error!("controller requested an unsupported subprotocol"));
 
-            return;
+            return e.into_response();
         }
     };
 
-    let mut lock = channel.write().await;
-    let sender = match lock.sender.take() {
-        Some(sender) => {
-            info!("sender found for device: {}", device);
+    // Clients that can't pick a subprotocol ask for JSON-RPC in the query instead.
+    let framing = if ws
+        .selected_protocol()
+        .is_some_and(|protocol| protocol.as_bytes() == JSONRPC_SUBPROTOCOL.as_bytes())
+        || params.get("protocol").is_some_and(|protocol| protocol == "jsonrpc")
+    {
+        Framing::JsonRpc
+    } else {
+        Framing::Native
+    };
 
-            sender
-        }
+    let options = SessionOptions { resume, framing };
 
-        None => {
-            error!("no sender found for device: {}", device);
+    let ws = ws
+        .max_frame_size(config.max_frame_size)
+        .max_message_size(config.max_ws_message_size);
 
-            return;
-        }
-    };
-    drop(lock);
+    // Without a player to control, the commands are echoed for client developers to test against.
+    if echo && config.echo_enabled && !state.channels.read().await.contains_key(&device) {
+        return ws
+            .on_upgrade(move |socket| handle_echo(socket, config, framing).instrument(span))
+            .into_response();
+    }
 
-    let mut controller_state = ControllerState::default();
+    // Nested in the route span, so the logs of the session carry the client and the device.
+    let span = info_span!(parent: &span, "session", device = %device);
 
-    while let Some(Ok(msg)) = socket.recv().await {
-        if sender.is_closed() {
+    return ws
+        .on_upgrade(move |socket| {
+            handle_controller(socket, state, config, addr, device, credential, options)
+                .instrument(span)
+        })
+        .into_response();
+}
+
+/// Turns binary frames carrying UTF-8 into text, for clients that can't send text.
+fn binary_as_text(msg: Message) -> Result<Message, FromUtf8Error> {
+    return match msg {
+        Message::Binary(bytes) => {
+            String::from_utf8(bytes.to_vec()).map(|text| Message::Text(Utf8Bytes::from(text)))
+        }
+        msg => Ok(msg),
+    };
+}
+
+/// Splits a message of a controller into its commands.
+///
+/// Controllers may batch commands as JSON Lines, which are applied in order. A message holding a
+/// single JSON document is one command, even over several lines.
+fn command_lines(text: &str) -> Vec<&str> {
+    if serde_json::from_str::<serde_json::Value>(text).is_ok() {
+        return vec![text];
+    }
+
+    return text.lines().filter(|line| !line.trim().is_empty()).collect();
+}
+
+/// Outcome of reading a command of a controller.
+enum Read {
+    /// The event to act on.
+    Event(Event),
+    /// Nothing to act on, as the command was answered or can be ignored.
+    Skip,
+    /// The session ends, closing the connection for the reason if any.
+    End(Option<CloseReason>),
+}
+
+/// Reads the commands of a controller in the framing it speaks, checking them against the
+/// configuration the same way whether they're forwarded or echoed.
+struct CommandReader {
+    framing: Framing,
+    /// Sequence number of the last event of the controller, which the next one must exceed.
+    last_seq: Option<u64>,
+}
+
+impl CommandReader {
+    fn new(framing: Framing) -> Self {
+        return Self {
+            framing,
+            last_seq: None,
+        };
+    }
+
+    /// Reads a command, checking its sequence number when they're enforced and whether it's
+    /// allowed.
+    ///
+    /// Invalid JSON-RPC requests are answered with their error. Any invalid command ends the
+    /// session when it came in a `batch`, as the ones after it may depend on it.
+    async fn read(
+        &mut self,
+        socket: &mut WebSocket,
+        line: &str,
+        batch: bool,
+        config: &Config,
+    ) -> Read {
+        let parsed = match self.framing {
+            Framing::Native => parse_message(line).map(|envelope| envelope.event),
+            Framing::JsonRpc => match rpc::parse_request(line) {
+                Ok(event) => Ok(event),
+                Err(rejection) => {
+                    error!("invalid JSON-RPC request: {}", rejection.error.message);
+
+                    let response = Message::text(rejection.response());
+
+                    if let Err(e) = send(socket, response, config.write_timeout).await {
+                        error!("failed to send error response to controller: {}", e);
+
+                        return Read::End(None);
+                    }
+
+                    return Read::Skip;
+                }
+            },
+        };
+
+        let event = match parsed {
+            Ok(event) => event,
+            Err(ProtocolError::UnsupportedSchema(version)) => {
+                error!("unsupported schema version: {}", version);
+
+                return Read::End(Some(CloseReason::UnsupportedSchema));
+            }
+            Err(ProtocolError::UnsupportedVersion(version)) => {
+                error!("unsupported protocol version: {}", version);
+
+                return Read::End(Some(CloseReason::ProtocolError));
+            }
+            Err(ProtocolError::Malformed(e)) if !e.is_data() => {
+                error!("message from controller isn't JSON: {}", e);
+
+                return Read::End(Some(CloseReason::ProtocolError));
+            }
+            Err(e) if batch => {
+                error!("failed to parse event in batch: {}", e);
+
+                return Read::End(Some(CloseReason::ProtocolError));
+            }
+            Err(e) => {
+                error!("failed to parse event: {}", e);
+
+                return Read::Skip;
+            }
+        };
+
+        debug!("received event on controller side: {:?}", event);
+
+        // Echoes of the ping events only show the controller is there.
+        if let Action::Ping = event.action {
+            return Read::Skip;
+        }
+
+        if config.enforce_seq {
+            match (event.seq, self.last_seq) {
+                (Some(seq), Some(last)) if seq <= last => {
+                    error!(seq, last, "event replayed or out of order");
+
+                    return Read::End(Some(CloseReason::ProtocolError));
+                }
+                (Some(seq), _) => self.last_seq = Some(seq),
+                (None, _) => {
+                    error!("event without sequence number");
+
+                    return Read::End(Some(CloseReason::ProtocolError));
+                }
+            }
+        }
+
+        if !config.allowed_commands.contains(&event.command()) {
+            error!("command isn't allowed: {:?}", event.command());
+
+            return Read::End(Some(CloseReason::ProtocolError));
+        }
+
+        return Read::Event(event);
+    }
+}
+
+/// Echoes every valid command of a controller back to it instead of forwarding it to a player,
+/// running the state machine as if one were attached.
+///
+/// The commands are read and limited as in a session with a player, the rate being counted per
+/// connection since there's no device to count it on.
+async fn handle_echo(mut socket: WebSocket, config: Arc<Config>, framing: Framing) {
+    let timeout = config.write_timeout;
+
+    if let Err(e) = send_framed(&mut socket, framing, server_info(), timeout).await {
+        error!("failed to send server info to controller: {}", e);
+
+        return;
+    }
+
+    info!("controller started in echo mode");
+
+    let mut controller_state = ControllerState::default();
+    let mut reader = CommandReader::new(framing);
+    let mut bucket = TokenBucket::default();
+
+    'session: loop {
+        let msg = match time::timeout(config.idle_timeout, socket.recv()).await {
+            Ok(Some(Ok(msg))) => msg,
+            Ok(_) => break,
+            Err(_) => {
+                warn!("controller idle for too long");
+
+                send_close(&mut socket, CloseReason::IdleTimeout, timeout).await;
+
+                break;
+            }
+        };
+
+        let text = match binary_as_text(msg) {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) => break,
+            Ok(_) => continue,
+            Err(e) => {
+                error!("binary message from controller isn't valid UTF-8: {}", e);
+
+                send_close(&mut socket, CloseReason::ProtocolError, timeout).await;
+
+                break;
+            }
+        };
+
+        if text.len() > config.max_message_size {
+            error!(size = text.len(), "message from controller is too big");
+
+            send_close(&mut socket, CloseReason::ProtocolError, timeout).await;
+
+            break;
+        }
+
+        let lines = command_lines(&text);
+        let batch = lines.len() > 1;
+
+        for line in lines {
+            let event = match reader.read(&mut socket, line, batch, &config).await {
+                Read::Event(event) => event,
+                Read::Skip => continue,
+                Read::End(reason) => {
+                    if let Some(reason) = reason {
+                        send_close(&mut socket, reason, timeout).await;
+                    }
+
+                    break 'session;
+                }
+            };
+
+            // Applied to a copy, so a throttled command leaves the state as it was.
+            let mut next = controller_state;
+
+            if let Err(e) = next.transition(event.command()) {
+                warn!("command ignored: {}", e);
+
+                continue;
+            }
+
+            // Unpairing is never throttled, as with a player.
+            if !matches!(event.action, Action::Unpair | Action::Reset)
+                && !bucket.take(config.commands_per_second)
+            {
+                debug!("command dropped: {:?}", event.command());
+
+                continue;
+            }
+
+            controller_state = next;
+
+            let echo = Message::text(serde_json::to_string(&event).unwrap());
+
+            if let Err(e) = send_framed(&mut socket, framing, echo, timeout).await {
+                error!("failed to echo command to controller: {}", e);
+
+                break 'session;
+            }
+
+            if let Action::Unpair = event.action {
+                info!("controller unpaired");
+
+                break 'session;
+            }
+        }
+    }
+
+    info!("echo session ended");
+}
+
+/// Cuts the text to at most `max` bytes, on a character boundary.
+fn excerpt(text: &str, max: usize) -> &str {
+    let mut end = max.min(text.len());
+
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    return &text[..end];
+}
+
+/// Builds the event ending the session of a player, which tells it it's unpaired.
+fn unpair_event() -> Event {
+    return Event::new(Action::Unpair);
+}
+
+/// Builds the event telling a player its controller let go of it, which unpairs it but keeps its
+/// session for another controller to pair with.
+fn release_event() -> Event {
+    return Event::new(Action::Reset);
+}
+
+/// Whether the player is expected to acknowledge the command, which is the case of the playback
+/// commands.
+fn awaits_ack(action: &Action) -> bool {
+    return matches!(
+        action,
+        Action::Play { .. }
+            | Action::Pause
+            | Action::Resume
+            | Action::Seek { .. }
+            | Action::SetVolume { .. }
+            | Action::Stop
+    );
+}
+
+/// Checks the URL of a `Play` has one of the allowed schemes.
+fn validate_url(url: &str, config: &Config) -> Result<(), String> {
+    let url = Url::parse(url).map_err(|e| e.to_string())?;
+
+    if !config.play_schemes.iter().any(|scheme| scheme == url.scheme()) {
+        return Err(format!("scheme {} isn't allowed", url.scheme()));
+    }
+
+    return Ok(());
+}
+
+/// Closes the connection, telling the client why.
+async fn send_close(socket: &mut WebSocket, reason: CloseReason, timeout: Duration) {
+    debug!(code = reason.code(), "closing websocket connection: {}", reason.reason());
+
+    if let Err(e) = send(socket, Message::Close(Some(reason.frame())), timeout).await {
+        error!("failed to close websocket connection: {}", e);
+    }
+}
+
+/// Takes a token from the command bucket of the device, returning whether the command is within
+/// the rate limit.
+async fn within_rate(state: &State, device: &str, rate: u32) -> bool {
+    return match state.channels.read().await.get(device) {
+        Some(channel) => channel.write().await.bucket.take(rate),
+        None => true,
+    };
+}
+
+/// What a controller presents to attach to a device.
+enum Credential {
+    /// Secret of the device.
+    Secret(Secret),
+    /// One-time token of the handoff offered by another controller of the device.
+    Handoff(Secret),
+}
+
+/// How the controller asked to run its session.
+#[derive(Debug, Clone, Copy)]
+struct SessionOptions {
+    /// Whether to resume from the state the last controller left the device in.
+    resume: bool,
+    framing: Framing,
+}
+
+/// Tells the controller offering a handoff to detach, see [`Handoff::detach`].
+type Detach = oneshot::Sender<oneshot::Sender<ControllerState>>;
+
+/// Attaches a controller to the device, returning the sender to its player, the state the last
+/// controller left the device in, the receiver for the player replies and, when it takes over
+/// through a handoff, the means to detach the controller that offered it.
+async fn attach(
+    state: &State,
+    device: &str,
+    credential: &Credential,
+) -> Result<
+    (
+        mpsc::Sender<Event>,
+        ControllerState,
+        broadcast::Receiver<Utf8Bytes>,
+        Option<Detach>,
+    ),
+    CloseReason,
+> {
+    let token = match state.channels.read().await.get(device) {
+        Some(channel) => channel.read().await.token.clone(),
+        None => {
+            error!("no channel found for device");
+
+            return Err(CloseReason::DeviceNotFound);
+        }
+    };
+
+    // The provider may be slow, like one backed by a remote store, so the secret is verified
+    // without holding the lock of the registry.
+    if let Credential::Secret(secret) = credential
+        && !state.auth.verify(device, secret).await
+    {
+        error!("invalid secret for device");
+
+        return Err(CloseReason::InvalidSecret);
+    }
+
+    let channels = state.channels.read().await;
+
+    let channel = match channels.get(device) {
+        Some(channel) => channel,
+        None => {
+            error!("no channel found for device");
+
+            return Err(CloseReason::DeviceNotFound);
+        }
+    };
+
+    let mut lock = channel.write().await;
+
+    // The secret was verified for the device found first, not one registered under its name since.
+    if lock.token != token {
+        error!("device was replaced while verifying the secret");
+
+        return Err(CloseReason::DeviceNotFound);
+    }
+
+    let detach = match credential {
+        Credential::Secret(_) => None,
+        // The handoff is only used up once taken over, so wrong guesses don't cancel it.
+        Credential::Handoff(token) => match lock.handoff.take() {
+            Some(handoff) if handoff.token == *token && !handoff.detach.is_closed() => {
+                Some(handoff.detach)
+            }
+            handoff => {
+                lock.handoff = handoff;
+
+                error!("invalid handoff token for device");
+
+                return Err(CloseReason::InvalidSecret);
+            }
+        },
+    };
+
+    info!("sender found for device");
+
+    lock.controllers += 1;
+
+    return Ok((lock.sender.clone(), lock.state, lock.replies.subscribe(), detach));
+}
+
+/// Waits for another controller to take over the handoff, if any, which never happens without
+/// one.
+async fn taken_over(
+    handoff: &mut Option<oneshot::Receiver<oneshot::Sender<ControllerState>>>,
+) -> Result<oneshot::Sender<ControllerState>, oneshot::error::RecvError> {
+    return match handoff {
+        Some(handoff) => handoff.await,
+        None => future::pending().await,
+    };
+}
+
+/// Queues up to `depth` commands of a controller in front of the channel of its player, forwarding
+/// them in order as the channel makes room, returning the sender of the queue.
+///
+/// The queue closes once the player is gone, as the channel does.
+fn queue(player: mpsc::Sender<Event>, depth: usize) -> mpsc::Sender<Event> {
+    let (sender, mut receiver) = mpsc::channel(depth);
+
+    let forward = async move {
+        loop {
+            let event = select! {
+                event = receiver.recv() => event,
+                _ = player.closed() => None,
+            };
+
+            let Some(event) = event else {
+                break;
+            };
+
+            if let Err(e) = player.send(event).await {
+                error!("failed to send queued command to player: {}", e);
+
+                metrics::forward_failed("closed");
+
+                break;
+            }
+        }
+    };
+
+    tokio::spawn(forward.in_current_span());
+
+    return sender;
+}
+
+async fn handle_controller(
+    mut socket: WebSocket,
+    state: Arc<State>,
+    config: Arc<Config>,
+    addr: SocketAddr,
+    device: String,
+    credential: Credential,
+    options: SessionOptions,
+) {
+    let SessionOptions { resume, framing } = options;
+    let timeout = config.write_timeout;
+
+    if let Err(e) = send_framed(&mut socket, framing, server_info(), timeout).await {
+        error!("failed to send server info to controller: {}", e);
+
+        return;
+    }
+
+    let attached = attach(&state, &device, &credential).await;
+
+    let (sender, last_state, mut replies, detach) = match attached {
+        Ok(attached) => attached,
+        Err(reason) => {
+            send_close(&mut socket, reason, timeout).await;
+
+            return;
+        }
+    };
+
+    let sender = match config.controller_queue {
+        Some(depth) => queue(sender, depth),
+        None => sender,
+    };
+
+    metrics::controller_connected();
+
+    // A controller taking over, or that lost its connection, resumes from where the previous one
+    // left instead of pairing again.
+    let mut controller_state = if let Some(detach) = detach {
+        let (reply, handed_over) = oneshot::channel();
+
+        let _ = detach.send(reply);
+
+        match time::timeout(timeout, handed_over).await {
+            Ok(Ok(handed_over)) => {
+                info!("controller took over in state {:?}", handed_over);
+
+                handed_over
+            }
+            _ => {
+                warn!("controller offering the handoff left before it was taken over");
+
+                ControllerState::default()
+            }
+        }
+    } else if resume {
+        info!("controller resumed in state {:?}", last_state);
+
+        last_state
+    } else {
+        ControllerState::default()
+    };
+
+    if let Credential::Handoff(_) = credential
+        && controller_state != ControllerState::Unpaired
+    {
+        state.publish(StateEvent::ControllerPaired {
+            device: device.clone(),
+        });
+
+        state.observer.on_controller_paired(&device);
+
+        let confirmation = Event::new(Action::Pair {
+            device: Some(device.clone()),
+        });
+
+        let confirmation = Message::text(serde_json::to_string(&confirmation).unwrap());
+
+        if let Err(e) = send_framed(&mut socket, framing, confirmation, timeout).await {
+            error!("failed to send pairing confirmation to controller: {}", e);
+        }
+    }
+
+    let mut paired_at = match controller_state {
+        ControllerState::Unpaired => None,
+        _ => Some(Instant::now()),
+    };
+
+    // Only messages from the controller itself count as activity, not the player replies.
+    let mut deadline = Instant::now() + config.idle_timeout;
+
+    // Whether commands are being dropped for exceeding the rate limit, to log it only once.
+    let mut throttled = false;
+
+    let mut reader = CommandReader::new(framing);
+
+    // Commands forwarded to the player that it hasn't acknowledged yet, by id, with the time the
+    // controller gets a timeout for them.
+    let mut in_flight: HashMap<String, Instant> = HashMap::new();
+
+    let mut next_app_ping = Instant::now() + config.app_ping_interval;
+
+    let expires_at = Instant::now() + config.max_session_duration;
+
+    // Handoff offered by the controller, told when another controller takes it over.
+    let mut handoff = None;
+
+    let mut stop = state.stop.subscribe();
+
+    'session: loop {
+        let expiry = in_flight.values().min().copied();
+
+        let msg = select! {
+            val = time::timeout_at(deadline, socket.recv()) => match val {
+                Ok(Some(Ok(msg))) => msg,
+                Ok(_) => break,
+                Err(_) => {
+                    warn!("controller idle for too long");
+
+                    // Only a paired controller has something to tear down on the player.
+                    if controller_state.transition(Command::Unpair).is_ok()
+                        && let Err(e) = sender.send(release_event()).await
+                    {
+                        error!("failed to send message from controller to player: {}", e);
+
+                        metrics::forward_failed("closed");
+                    }
+
+                    send_close(&mut socket, CloseReason::IdleTimeout, timeout).await;
+
+                    break;
+                }
+            },
+            val = replies.recv() => {
+                match val {
+                    Ok(reply) => {
+                        // Any answer from the player settles the command, so it won't time out.
+                        if let Ok(envelope) = parse_message(&reply)
+                            && let Action::Ack | Action::Nack { .. } = envelope.event.action
+                            && let Some(id) = envelope.event.id
+                        {
+                            in_flight.remove(&id);
+                        }
+
+                        let reply = Message::Text(reply);
+
+                        if let Err(e) = send_framed(&mut socket, framing, reply, timeout).await {
+                            error!("failed to send player reply to controller: {}", e);
+
+                            break;
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("controller missed {} player replies", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+
+                continue;
+            }
+            _ = stop.recv() => {
+                info!("controller session stopped by the server");
+
+                if let Err(e) = send(&mut socket, Message::Close(None), timeout).await {
+                    error!("failed to close websocket connection: {}", e);
+                }
+
+                break;
+            }
+            _ = time::sleep_until(expiry.unwrap_or(deadline)), if expiry.is_some() => {
+                let now = Instant::now();
+                let mut expired = Vec::new();
+
+                in_flight.retain(|id, expiry| {
+                    if *expiry > now {
+                        return true;
+                    }
+
+                    expired.push(id.clone());
+
+                    false
+                });
+
+                for id in expired {
+                    warn!(id = id, "player didn't acknowledge command in time");
+
+                    let nack = Message::text(
+                        serde_json::to_string(&Event {
+                            action: Action::Nack {
+                                reason: Some("timeout".to_string()),
+                            },
+                            id: Some(id),
+                            schema_version: SCHEMA_VERSION,
+                            seq: None,
+                        })
+                        .unwrap(),
+                    );
+
+                    if let Err(e) = send_framed(&mut socket, framing, nack, timeout).await {
+                        error!("failed to send timeout to controller: {}", e);
+
+                        break 'session;
+                    }
+                }
+
+                continue;
+            }
+            _ = time::sleep_until(next_app_ping), if !config.app_ping_interval.is_zero() => {
+                next_app_ping = Instant::now() + config.app_ping_interval;
+
+                trace!("sending ping event to controller");
+
+                if let Err(e) = send_framed(&mut socket, framing, app_ping(), timeout).await {
+                    error!("failed to send ping event to controller: {}", e);
+
+                    break;
+                }
+
+                continue;
+            }
+            reply = taken_over(&mut handoff) => {
+                handoff = None;
+
+                let Ok(reply) = reply else {
+                    continue;
+                };
+
+                info!("control of the device transferred to another controller");
+
+                // The player now belongs to the new controller, so it's left paired.
+                let _ = reply.send(controller_state);
+
+                controller_state = ControllerState::Unpaired;
+
+                let unpair = Message::text(serde_json::to_string(&unpair_event()).unwrap());
+
+                if let Err(e) = send_framed(&mut socket, framing, unpair, timeout).await {
+                    error!("failed to send unpair message to controller: {}", e);
+                }
+
+                send_close(&mut socket, CloseReason::Transferred, timeout).await;
+
+                break;
+            }
+            _ = time::sleep_until(expires_at), if !config.max_session_duration.is_zero() => {
+                info!("controller session expired");
+
+                // The player is let go of, for the controller to pair again.
+                if controller_state.transition(Command::Unpair).is_ok()
+                    && let Err(e) = sender.send(release_event()).await
+                {
+                    error!("failed to send message from controller to player: {}", e);
+
+                    metrics::forward_failed("closed");
+                }
+
+                let unpair = Message::text(serde_json::to_string(&unpair_event()).unwrap());
+
+                if let Err(e) = send_framed(&mut socket, framing, unpair, timeout).await {
+                    error!("failed to send unpair message to controller: {}", e);
+                }
+
+                send_close(&mut socket, CloseReason::SessionExpired, timeout).await;
+
+                break;
+            }
+        };
+
+        deadline = Instant::now() + config.idle_timeout;
+
+        if sender.is_closed() {
             debug!("websocket of the screen is closed");
 
+            metrics::forward_failed("closed");
+
             break;
         }
 
+        let msg = match binary_as_text(msg) {
+            Ok(msg) => msg,
+            Err(e) => {
+                error!("binary message from controller isn't valid UTF-8: {}", e);
+
+                send_close(&mut socket, CloseReason::ProtocolError, timeout).await;
+
+                break;
+            }
+        };
+
         match msg {
             Message::Text(text) => {
-                println!(
-                    "received message: {:?}",
-                    String::from_utf8_lossy(text.as_bytes())
+                if text.len() > config.max_message_size {
+                    error!(size = text.len(), "message from controller is too big");
+
+                    send_close(&mut socket, CloseReason::ProtocolError, timeout).await;
+
+                    break;
+                }
+
+                trace!(
+                    size = text.len(),
+                    message = excerpt(&text, TRACED_MESSAGE_BYTES),
+                    "received message from controller",
                 );
 
-                let event: Event = match serde_json::from_str(&text) {
-                    Ok(event) => event,
-                    Err(e) => {
-                        error!("failed to parse event: {}", e);
+                let lines = command_lines(&text);
+
+                let batch = lines.len() > 1;
+
+                for line in lines {
+                    let event = match reader.read(&mut socket, line, batch, &config).await {
+                        Read::Event(event) => event,
+                        Read::Skip => continue,
+                        Read::End(reason) => {
+                            if let Some(reason) = reason {
+                                send_close(&mut socket, reason, timeout).await;
+                            }
+
+                            break 'session;
+                        }
+                    };
+
+                    // Offered rather than forwarded, the player keeps going whoever controls it.
+                    if let Action::TransferTo { token } = &event.action {
+                        if let Err(e) = controller_state.transition(event.command()) {
+                            warn!("command ignored: {}", e);
+
+                            metrics::command_dropped("invalid_state");
+
+                            continue;
+                        }
+
+                        metrics::command(&event.command());
+
+                        let (detach, detached) = oneshot::channel();
+
+                        if let Some(channel) = state.channels.read().await.get(&device) {
+                            // Replaces the previous offer, whose receiver is dropped below.
+                            channel.write().await.handoff = Some(Handoff {
+                                token: Secret::from(token.as_str()),
+                                detach,
+                            });
+                        }
+
+                        handoff = Some(detached);
+
+                        info!("control of the device offered to another controller");
+
                         continue;
                     }
-                };
 
-                debug!("received event on controller side: {:?}", event);
+                    // Answered from the server's state, which is the authoritative one.
+                    if let Action::GetState = event.action {
+                        metrics::command(&event.command());
+
+                        let reply = Message::text(
+                            serde_json::to_string(&Event {
+                                action: Action::State {
+                                    state: format!("{:?}", controller_state),
+                                },
+                                id: event.id.clone(),
+                                schema_version: SCHEMA_VERSION,
+                                seq: None,
+                            })
+                            .unwrap(),
+                        );
+
+                        if let Err(e) = send_framed(&mut socket, framing, reply, timeout).await {
+                            error!("failed to send state to controller: {}", e);
+
+                            break 'session;
+                        }
 
-                match event.command {
-                    Command::Pair => {
-                        if !controller_state.pair() {
-                            error!("controller already paired");
+                        continue;
+                    }
 
-                            let close = Utf8Bytes::from(
-                                serde_json::to_string(&Event {
-                                    command: Command::Unpair,
-                                    payload: None,
-                                })
-                                .unwrap(),
-                            );
+                    let forward = match &event.action {
+                        Action::Play { url } => {
+                            if let Err(e) = validate_url(url, &config) {
+                                error!("invalid play url {:?}: {}", url, e);
 
-                            if let Err(e) = sender.send(close).await {
-                                error!("failed to send message from controller to player: {}", e);
+                                send_close(&mut socket, CloseReason::ProtocolError, timeout).await;
+
+                                break 'session;
                             }
 
-                            break;
+                            event.clone()
                         }
+                        Action::Seek { position } => {
+                            if !position.is_finite() || *position < 0.0 {
+                                error!("invalid seek position: {}", position);
 
-                        info!("controller paired");
+                                send_close(&mut socket, CloseReason::ProtocolError, timeout).await;
 
-                        sender.send(text).await.unwrap();
-                    }
-                    Command::Play => {
-                        if !controller_state.play() {
-                            error!("controller already playing");
+                                break 'session;
+                            }
 
-                            let close = Utf8Bytes::from(
-                                serde_json::to_string(&Event {
-                                    command: Command::Unpair,
-                                    payload: None,
-                                })
-                                .unwrap(),
-                            );
+                            event.clone()
+                        }
+                        Action::SetVolume { level } => Event {
+                            action: Action::SetVolume {
+                                level: (*level).min(100),
+                            },
+                            id: event.id.clone(),
+                            schema_version: SCHEMA_VERSION,
+                            seq: event.seq,
+                        },
+                        // The player stays for the next controller to pair with it.
+                        Action::Unpair => Event {
+                            action: Action::Reset,
+                            id: event.id.clone(),
+                            schema_version: SCHEMA_VERSION,
+                            seq: event.seq,
+                        },
+                        _ => event.clone(),
+                    };
+
+                    // Applied to a copy, so a throttled command leaves the state as it was.
+                    let mut next = controller_state;
+                    let mut replace = false;
+
+                    if let Err(e) = next.transition(event.command()) {
+                        match event.action {
+                            Action::Pair { .. } => match config.pair_policy {
+                                PairPolicy::Ignore => {
+                                    warn!("controller already paired, pair ignored");
+
+                                    metrics::command_dropped("invalid_state");
+
+                                    continue;
+                                }
+                                PairPolicy::Error => {
+                                    error!("controller already paired");
+
+                                    if let Err(e) = sender.send(release_event()).await {
+                                        error!(
+                                            "failed to send message from controller to player: {}",
+                                            e
+                                        );
+
+                                        metrics::forward_failed("closed");
+                                    }
+
+                                    send_close(&mut socket, CloseReason::AlreadyPaired, timeout)
+                                        .await;
+
+                                    break 'session;
+                                }
+                                PairPolicy::Replace => {
+                                    next = ControllerState::Paired;
+                                    replace = true;
+                                }
+                            },
+                            Action::Unpair => {
+                                info!("controller left without pairing");
 
-                            if let Err(e) = sender.send(close).await {
-                                error!("failed to send message from controller to player: {}", e);
+                                break 'session;
                             }
+                            _ => {
+                                warn!("command ignored: {}", e);
 
-                            break;
+                                metrics::command_dropped("invalid_state");
+
+                                continue;
+                            }
+                        }
+                    }
+
+                    // Unpairing is never throttled, so a controller can always let go of the
+                    // device.
+                    if !matches!(event.action, Action::Unpair | Action::Reset)
+                        && !within_rate(&state, &device, config.commands_per_second).await
+                    {
+                        if !throttled {
+                            warn!("controller exceeds the command rate, throttling");
                         }
 
-                        info!("playing file");
+                        throttled = true;
 
-                        sender.send(text).await.unwrap();
+                        debug!("command dropped: {:?}", event.command());
+
+                        metrics::command_dropped("rate_limited");
+
+                        continue;
                     }
-                    Command::Stop => {
-                        if !controller_state.stop() {
-                            error!("controller not playing");
 
-                            let close = Utf8Bytes::from(
-                                serde_json::to_string(&Event {
-                                    command: Command::Unpair,
-                                    payload: None,
-                                })
-                                .unwrap(),
-                            );
+                    throttled = false;
+
+                    // Only counted once valid, the rejected ones are counted as dropped.
+                    metrics::command(&event.command());
 
-                            if let Err(e) = sender.send(close).await {
-                                error!("failed to send message from controller to player: {}", e);
+                    if replace {
+                        info!("controller already paired, pairing again");
+
+                        // The player is let go of first, so it pairs from a clean state as with a
+                        // new controller.
+                        if let Err(e) = sender.send(release_event()).await {
+                            error!("failed to send message from controller to player: {}", e);
+
+                            metrics::forward_failed("closed");
+                        }
+
+                        if let Some(paired_at) = paired_at.take() {
+                            metrics::session_ended(paired_at.elapsed());
+                        }
+                    }
+
+                    controller_state = next;
+
+                    // Commands are rejected rather than awaited when the player, and the queue of
+                    // the controller if any, can't keep up.
+                    match sender.try_send(forward) {
+                        Ok(_) => {
+                            info!("command forwarded to player: {:?}", event.command());
+
+                            state.publish(StateEvent::CommandForwarded {
+                                device: device.clone(),
+                                command: event.command(),
+                            });
+
+                            config.audit.record(&AuditRecord {
+                                device: device.clone(),
+                                command: event.command(),
+                                timestamp: SystemTime::now(),
+                                ip: addr.ip(),
+                            });
+
+                            state.observer.on_command(&device, &event);
+
+                            if let Some(id) = &event.id
+                                && !config.ack_timeout.is_zero()
+                                && awaits_ack(&event.action)
+                            {
+                                in_flight.insert(id.clone(), Instant::now() + config.ack_timeout);
                             }
+                        }
+                        Err(TrySendError::Full(_)) => {
+                            warn!("player channel is full, rejecting command");
 
-                            break;
+                            metrics::command_dropped("backpressure");
+
+                            send_close(&mut socket, CloseReason::Backpressure, timeout).await;
+
+                            break 'session;
                         }
+                        Err(e) => {
+                            error!("failed to send message from controller to player: {}", e);
 
-                        info!("stopping file");
+                            metrics::forward_failed("closed");
 
-                        sender.send(text).await.unwrap();
+                            break 'session;
+                        }
                     }
-                    Command::Unpair => {
-                        if !controller_state.unpair() {
-                            error!("controller already unpaired");
 
-                            let close = Utf8Bytes::from(
+                    match event.action {
+                        Action::Pair { .. } => {
+                            info!("controller paired");
+
+                            state.publish(StateEvent::ControllerPaired {
+                                device: device.clone(),
+                            });
+
+                            state.observer.on_controller_paired(&device);
+
+                            paired_at = Some(Instant::now());
+
+                            let confirmation = Message::text(
                                 serde_json::to_string(&Event {
-                                    command: Command::Unpair,
-                                    payload: None,
+                                    action: Action::Pair {
+                                        device: Some(device.clone()),
+                                    },
+                                    id: event.id.clone(),
+                                    schema_version: SCHEMA_VERSION,
+                                    seq: None,
                                 })
                                 .unwrap(),
                             );
 
-                            if let Err(e) = sender.send(close).await {
-                                error!("failed to send message from controller to player: {}", e);
+                            let sent = send_framed(&mut socket, framing, confirmation, timeout);
+
+                            if let Err(e) = sent.await {
+                                error!("failed to send pairing confirmation to controller: {}", e);
+
+                                break 'session;
                             }
                         }
+                        Action::Unpair => {
+                            info!("controller unpaired");
 
-                        info!("controller unpaired");
-
-                        sender.send(text).await.unwrap();
+                            break 'session;
+                        }
+                        Action::Reset => {
+                            info!("controller reset");
 
-                        break;
+                            // The pairing session ends, but the connection stays open for another.
+                            if let Some(paired_at) = paired_at.take() {
+                                metrics::session_ended(paired_at.elapsed());
+                            }
+                        }
+                        _ => {}
                     }
                 }
             }
             Message::Close(_) => {
                 info!("websocket connection received a close message on controller side");
 
-                if !controller_state.unpair() {
-                    error!("device already playing");
-
-                    let close = Utf8Bytes::from(
-                        serde_json::to_string(&Event {
-                            command: Command::Unpair,
-                            payload: None,
-                        })
-                        .unwrap(),
-                    );
+                // Only a paired controller has something to tear down on the player.
+                if controller_state.transition(Command::Unpair).is_ok() {
+                    let close = release_event();
 
                     if let Err(e) = sender.send(close).await {
                         error!("failed to send message from controller to player: {}", e);
+
+                        metrics::forward_failed("closed");
                     }
                 }
 
-                match socket.send(Message::Close(None)).await {
+                match send(&mut socket, Message::Close(None), timeout).await {
                     Ok(_) => {
                         info!("websocket connection send close message on controller side");
                     }
@@ -464,11 +1894,262 @@ async fn handle_controller(
 
     trace!("controller websocket loop exited");
 
+    // Every way out of the loop ends the pairing session, whether it was unpaired or not.
+    if let Some(paired_at) = paired_at {
+        let duration = paired_at.elapsed();
+
+        info!(
+            duration = duration.as_secs_f64(),
+            "controller session ended",
+        );
+
+        metrics::session_ended(duration);
+    }
+
     if let ControllerState::Unpaired = controller_state {
         debug!("controller status is unpaired as expected");
     } else {
         warn!("controller loop existed without being unpaired");
     }
 
+    if let Some(channel) = state.channels.read().await.get(&device) {
+        let mut lock = channel.write().await;
+        lock.controllers = lock.controllers.saturating_sub(1);
+        lock.state = controller_state;
+    }
+
+    state.observer.on_disconnected(&device, Peer::Controller);
+
+    metrics::controller_disconnected();
+
     info!("websocket connection closed on controller side");
 }
+
+/// Status of a registered device, as reported to administrators.
+#[derive(Serialize)]
+struct DeviceStatus {
+    /// Device name.
+    device: String,
+    /// Whether a controller is attached to the device, paired with it or not.
+    controlled: bool,
+}
+
+/// Checks the `Authorization` header against the configured admin token.
+fn authorized(headers: &HeaderMap, config: &Config) -> bool {
+    let Some(token) = &config.admin_token else {
+        return false;
+    };
+
+    return match headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+    {
+        // Compared in constant time, so the time taken doesn't leak how much of a guess matches.
+        Some(value) => {
+            let value = value.strip_prefix("Bearer ").unwrap_or(value);
+
+            value.as_bytes().ct_eq(token.as_bytes()).into()
+        }
+        None => false,
+    };
+}
+
+/// Lists the registered devices and whether a controller is attached to each, for administrators.
+pub async fn devices(
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Response {
+    if !authorized(&headers, &config) {
+        warn!("unauthorized request to the devices route");
+
+        return ApiError::new(ErrorCode::Unauthorized, "invalid admin token").into_response();
+    }
+
+    let devices: Vec<DeviceStatus> = state
+        .snapshot()
+        .await
+        .into_iter()
+        .map(|info| DeviceStatus {
+            device: info.device,
+            controlled: info.attached,
+        })
+        .collect();
+
+    return Json(devices).into_response();
+}
+
+/// Unpairs the player of the device and removes the device, for operators to end a session.
+pub async fn disconnect(
+    headers: HeaderMap,
+    Path(device): Path<String>,
+    Extension(state): Extension<Arc<State>>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Response {
+    if !authorized(&headers, &config) {
+        warn!("unauthorized request to disconnect a device");
+
+        return ApiError::new(ErrorCode::Unauthorized, "invalid admin token").into_response();
+    }
+
+    let sender = match state.channels.read().await.get(&device) {
+        Some(channel) => channel.read().await.sender.clone(),
+        None => {
+            return ApiError::new(ErrorCode::DeviceNotFound, "device not found").into_response();
+        }
+    };
+
+    // The player closes its connection once it gets the unpair, so the device is only removed
+    // when the player was told about it.
+    let error = match time::timeout(config.write_timeout, sender.send(unpair_event())).await {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some("timed out".to_string()),
+    };
+
+    if let Some(error) = error {
+        warn!(device = device, error = error, "failed to unpair device");
+
+        return ApiError::new(ErrorCode::PlayerUnreachable, "player didn't take the unpair")
+            .into_response();
+    }
+
+    state.unregister_device(&device).await;
+
+    info!(device = device, "device disconnected by an operator");
+
+    return StatusCode::NO_CONTENT.into_response();
+}
+
+pub async fn admin(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Extension(state): Extension<Arc<State>>,
+    Extension(config): Extension<Arc<Config>>,
+) -> Response {
+    if !authorized(&headers, &config) {
+        warn!("unauthorized request to the admin route");
+
+        return ApiError::new(ErrorCode::Unauthorized, "invalid admin token").into_response();
+    }
+
+    let span = info_span!("admin");
+
+    // Subscribing before the upgrade means no event is missed once the client is connected.
+    let events = state.events.subscribe();
+
+    return ws
+        .on_upgrade(move |socket| handle_admin(socket, config, events).instrument(span))
+        .into_response();
+}
+
+/// Streams the state changes to an administrator until it disconnects.
+async fn handle_admin(
+    mut socket: WebSocket,
+    config: Arc<Config>,
+    mut events: broadcast::Receiver<StateEvent>,
+) {
+    info!("admin connected");
+
+    loop {
+        select! {
+            val = socket.recv() => match val {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            val = events.recv() => match val {
+                Ok(event) => {
+                    let msg = Message::text(serde_json::to_string(&event).unwrap());
+
+                    if let Err(e) = send(&mut socket, msg, config.write_timeout).await {
+                        error!("failed to send state event to admin: {}", e);
+
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("admin missed {} state events", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+        }
+    }
+
+    info!("admin disconnected");
+}
+
+/// Sends an unpair event to every registered player, which closes their sessions.
+pub async fn unpair_all(state: &State) {
+    let unpair = unpair_event();
+
+    let mut senders = Vec::new();
+
+    // The lock isn't held while sending, for a full player not to block the registry.
+    for (device, channel) in state.channels.read().await.iter() {
+        senders.push((device.clone(), channel.read().await.sender.clone()));
+    }
+
+    let sends = senders.into_iter().map(|(device, sender)| {
+        let unpair = unpair.clone();
+
+        return async move {
+            if let Err(e) = sender.send(unpair).await {
+                error!(device = device, error = e.to_string(), "failed to unpair device");
+            }
+        };
+    });
+
+    join_all(sends).await;
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    /// Number of registered devices.
+    devices: usize,
+}
+
+/// Commit the server was built from, set by the `GIT_COMMIT` variable at build time.
+const GIT_COMMIT: &str = match option_env!("GIT_COMMIT") {
+    Some(commit) => commit,
+    None => "unknown",
+};
+
+/// Build and runtime information, for support.
+#[derive(Serialize)]
+struct Info {
+    version: &'static str,
+    commit: &'static str,
+    /// Seconds since the server started.
+    uptime: f64,
+    /// Number of registered devices.
+    devices: usize,
+    /// Number of controllers attached to the devices.
+    controllers: usize,
+}
+
+pub async fn info(Extension(state): Extension<Arc<State>>) -> impl IntoResponse {
+    let channels = state.channels.read().await;
+    let mut controllers = 0;
+
+    for channel in channels.values() {
+        controllers += channel.read().await.controllers;
+    }
+
+    return Json(Info {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: GIT_COMMIT,
+        uptime: state.started.elapsed().as_secs_f64(),
+        devices: channels.len(),
+        controllers,
+    });
+}
+
+pub async fn health(Extension(state): Extension<Arc<State>>) -> impl IntoResponse {
+    let devices = state.channels.read().await.len();
+
+    return Json(Health {
+        status: "ok",
+        devices,
+    });
+}
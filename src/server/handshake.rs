@@ -0,0 +1,171 @@
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::serve::Listener;
+use axum_server::accept::Accept;
+use futures::future::BoxFuture;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream},
+    time::{self, Instant, Sleep},
+};
+
+/// Connection that must get its first response before a deadline, so clients stalling the
+/// handshake don't hold on to it.
+///
+/// The server only writes once it read a whole request, so the first write ends the handshake,
+/// whether it's the upgrade to a WebSocket or a plain HTTP response.
+pub struct Handshake<T> {
+    inner: T,
+    /// Deadline of the handshake, unset once it's done or when there is none.
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> Handshake<T> {
+    /// Wraps the connection with a deadline, where `None` waits forever.
+    pub fn new(inner: T, deadline: Option<Instant>) -> Self {
+        Self {
+            inner,
+            deadline: deadline.map(|deadline| Box::pin(time::sleep_until(deadline))),
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Handshake<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(deadline) = self.deadline.as_mut() {
+            if deadline.as_mut().poll(cx).is_ready() {
+                let error = io::Error::new(io::ErrorKind::TimedOut, "handshake timed out");
+
+                return Poll::Ready(Err(error));
+            }
+        }
+
+        return Pin::new(&mut self.inner).poll_read(cx, buf);
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Handshake<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.deadline = None;
+
+        return Pin::new(&mut self.inner).poll_write(cx, buf);
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.deadline = None;
+
+        return Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        return self.inner.is_write_vectored();
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.inner).poll_flush(cx);
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        return Pin::new(&mut self.inner).poll_shutdown(cx);
+    }
+}
+
+/// Deadline of a handshake starting now, where a zero timeout means there is none.
+fn deadline(timeout: Duration) -> Option<Instant> {
+    if timeout.is_zero() {
+        return None;
+    }
+
+    return Some(Instant::now() + timeout);
+}
+
+/// Listener giving its connections `timeout` to complete their handshake.
+pub struct HandshakeListener {
+    listener: TcpListener,
+    timeout: Duration,
+}
+
+impl HandshakeListener {
+    pub fn new(listener: TcpListener, timeout: Duration) -> Self {
+        Self { listener, timeout }
+    }
+}
+
+impl Listener for HandshakeListener {
+    type Io = Handshake<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        let (stream, addr) = Listener::accept(&mut self.listener).await;
+
+        return (Handshake::new(stream, deadline(self.timeout)), addr);
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        return Listener::local_addr(&self.listener);
+    }
+}
+
+/// Acceptor giving the connections `timeout` to complete their handshake, including the one of
+/// the acceptor it wraps.
+#[derive(Clone)]
+pub struct HandshakeAcceptor<A> {
+    inner: A,
+    timeout: Duration,
+}
+
+impl<A> HandshakeAcceptor<A> {
+    pub fn new(inner: A, timeout: Duration) -> Self {
+        Self { inner, timeout }
+    }
+}
+
+impl<A, I, S> Accept<I, S> for HandshakeAcceptor<A>
+where
+    A: Accept<I, S>,
+    A::Future: Send + 'static,
+    A::Stream: 'static,
+    A::Service: 'static,
+{
+    type Stream = Handshake<A::Stream>;
+    type Service = A::Service;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let deadline = deadline(self.timeout);
+        let accepted = self.inner.accept(stream, service);
+
+        return Box::pin(async move {
+            let (stream, service) = match deadline {
+                Some(deadline) => match time::timeout_at(deadline, accepted).await {
+                    Ok(accepted) => accepted?,
+                    Err(_) => {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"));
+                    }
+                },
+                None => accepted.await?,
+            };
+
+            return Ok((Handshake::new(stream, deadline), service));
+        });
+    }
+}
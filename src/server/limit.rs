@@ -0,0 +1,166 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::time;
+use tracing::warn;
+
+use crate::server::error::{ApiError, ErrorCode};
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Header set by the Fly.io proxy to the IP of the client.
+const FLY_CLIENT_IP: &str = "fly-client-ip";
+
+/// Limits how many requests each client IP can make per minute.
+pub struct RateLimiter {
+    /// Requests allowed per minute, where zero disables the limit.
+    limit: u32,
+    /// Proxies whose headers tell the IP of the client they forward.
+    trusted_proxies: Vec<IpAddr>,
+    /// Start of the current window and requests made in it, per client.
+    clients: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, trusted_proxies: Vec<IpAddr>) -> Self {
+        Self {
+            limit,
+            trusted_proxies,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a request from `ip`, returning whether it's within the limit.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+
+        let (start, requests) = clients.entry(ip).or_insert((now, 0));
+
+        if now.duration_since(*start) >= WINDOW {
+            *start = now;
+            *requests = 0;
+        }
+
+        *requests += 1;
+
+        return *requests <= self.limit;
+    }
+
+    /// Forgets the clients whose window is over, returning how many were forgotten.
+    pub fn sweep(&self) -> usize {
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        let before = clients.len();
+
+        clients.retain(|_, (start, _)| now.duration_since(*start) < WINDOW);
+
+        return before - clients.len();
+    }
+
+    /// Returns the IP of the client behind `peer`.
+    ///
+    /// A trusted proxy tells it with `Fly-Client-IP`, or else with `X-Forwarded-For`, where the
+    /// last address that isn't a trusted proxy is taken since the ones before it may be forged by
+    /// the client. Any other peer is the client.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trusted_proxies.contains(&peer) {
+            return peer;
+        }
+
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+        if let Some(ip) = header(FLY_CLIENT_IP).and_then(|ip| ip.trim().parse::<IpAddr>().ok()) {
+            return ip;
+        }
+
+        let forwarded = header("x-forwarded-for")
+            .unwrap_or_default()
+            .rsplit(',')
+            .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+            .find(|ip| !self.trusted_proxies.contains(ip));
+
+        return forwarded.unwrap_or(peer);
+    }
+}
+
+/// Forgets the clients of the limiter whose window is over, once per window, so the requests
+/// don't pay for it.
+pub async fn sweep(limiter: Arc<RateLimiter>) {
+    let mut interval = time::interval(WINDOW);
+
+    loop {
+        interval.tick().await;
+
+        limiter.sweep();
+    }
+}
+
+/// Bucket of the commands a device accepts, refilled continuously and holding up to a second's
+/// worth of them.
+#[derive(Debug, Default)]
+pub struct TokenBucket {
+    /// Tokens left and when they were counted, unset until the first command.
+    tokens: Option<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Takes a token from the bucket refilled at `rate` tokens per second, returning whether one
+    /// was left, where a zero rate disables the limit.
+    pub fn take(&mut self, rate: u32) -> bool {
+        if rate == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let rate = rate as f64;
+
+        let tokens = match self.tokens {
+            Some((tokens, counted)) => {
+                (tokens + now.duration_since(counted).as_secs_f64() * rate).min(rate)
+            }
+            None => rate,
+        };
+
+        if tokens < 1.0 {
+            self.tokens = Some((tokens, now));
+
+            return false;
+        }
+
+        self.tokens = Some((tokens - 1.0, now));
+
+        return true;
+    }
+}
+
+pub async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = limiter.client_ip(addr.ip(), request.headers());
+
+    if !limiter.check(ip) {
+        warn!(ip = ip.to_string(), "rate limit exceeded");
+
+        return ApiError::new(ErrorCode::RateLimited, "too many requests").into_response();
+    }
+
+    return next.run(request).await;
+}
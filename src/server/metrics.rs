@@ -0,0 +1,58 @@
+use std::{sync::OnceLock, time::Duration};
+
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use tracing::error;
+
+use crate::server::protocol::Command;
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Returns the handle to the Prometheus recorder, installing it on first use.
+pub fn handle() -> &'static PrometheusHandle {
+    return HANDLE.get_or_init(|| {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+
+        if let Err(e) = ::metrics::set_global_recorder(recorder) {
+            error!(error = e.to_string(), "failed to install metrics recorder");
+        }
+
+        handle
+    });
+}
+
+pub fn player_connected() {
+    ::metrics::gauge!("active_players").increment(1.0);
+}
+
+pub fn player_disconnected() {
+    ::metrics::gauge!("active_players").decrement(1.0);
+}
+
+pub fn controller_connected() {
+    ::metrics::gauge!("active_controllers").increment(1.0);
+}
+
+pub fn controller_disconnected() {
+    ::metrics::gauge!("active_controllers").decrement(1.0);
+}
+
+pub fn command(command: &Command) {
+    ::metrics::counter!("commands_total", "command" => format!("{:?}", command)).increment(1);
+}
+
+pub fn command_dropped(reason: &'static str) {
+    ::metrics::counter!("commands_dropped_total", "reason" => reason).increment(1);
+}
+
+pub fn forward_failed(reason: &'static str) {
+    ::metrics::counter!("forward_errors_total", "reason" => reason).increment(1);
+}
+
+pub fn session_ended(duration: Duration) {
+    ::metrics::histogram!("session_duration_seconds").record(duration.as_secs_f64());
+}
+
+pub async fn render() -> String {
+    return handle().render();
+}
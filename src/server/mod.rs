@@ -1,61 +1,567 @@
+pub mod access;
+pub mod audit;
+pub mod auth;
+pub mod config;
+pub mod error;
 mod handlers;
+mod handshake;
+mod limit;
+mod metrics;
+pub mod observer;
+pub mod protocol;
+pub mod rpc;
+pub mod secret;
 pub mod state;
+pub mod state_machine;
 
-use std::{io::Error, sync::Arc};
-use tokio::net::TcpListener;
+#[cfg(test)]
+pub(crate) mod tests;
+
+use axum_server::tls_rustls::RustlsConfig;
+use futures::future::try_join_all;
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use std::{future::IntoFuture, io, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::{TcpListener, lookup_host},
+    signal,
+    sync::watch,
+    time,
+};
 
 use axum::{
     Router,
     extract::Extension,
-    http::{self, HeaderName, HeaderValue},
-    routing::get,
-    serve,
+    http::{self, HeaderValue},
+    middleware,
+    routing::{delete, get},
+    serve::{self, ListenerExt},
 };
 
 use tower::ServiceBuilder;
 use tower_http::{
-    self, compression::CompressionLayer, cors::CorsLayer, limit::RequestBodyLimitLayer,
-    propagate_header::PropagateHeaderLayer, trace::TraceLayer,
+    self,
+    compression::{
+        CompressionLayer, CompressionLevel,
+        predicate::{NotForContentType, Predicate, SizeAbove},
+    },
+    cors::CorsLayer,
+    limit::RequestBodyLimitLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::{error, info, warn};
+
+pub use crate::server::config::Config;
+use crate::server::{
+    config::DEFAULT_BACKLOG,
+    error::{Result, ServerError},
+    handshake::{HandshakeAcceptor, HandshakeListener},
+    limit::RateLimiter,
+    state::State,
 };
 
-const REQUEST_BODY_LIMIT: usize = 16;
+const DEFAULT_ORIGIN: &str = "https://televiu.fly.dev";
+
+/// Maximum factor the bind retry delay is multiplied by, so the wait stays bounded.
+const MAX_BIND_BACKOFF: u32 = 32;
+
+/// Maximum factor the reap interval is multiplied by while there are no orphaned devices.
+const MAX_REAP_BACKOFF: u32 = 8;
+
+/// Parses the configured origins into header values, skipping the invalid ones.
+fn origins(configured: &[String]) -> Vec<HeaderValue> {
+    if configured.is_empty() {
+        warn!(value = DEFAULT_ORIGIN, "no CORS origins configured, using default");
+
+        return vec![HeaderValue::from_static(DEFAULT_ORIGIN)];
+    }
+
+    let mut origins = Vec::with_capacity(configured.len());
+
+    for origin in configured {
+        match HeaderValue::from_str(origin) {
+            Ok(value) => origins.push(value),
+            Err(e) => {
+                error!(
+                    origin = origin,
+                    error = e.to_string(),
+                    "failed to parse CORS origin, skipping",
+                );
+            }
+        }
+    }
+
+    return origins;
+}
+
+/// Builds the CORS layer for the origins, falling back to the shared ones when none are given.
+fn cors(configured: &[String], config: &Config) -> CorsLayer {
+    let configured = if configured.is_empty() {
+        &config.origins
+    } else {
+        configured
+    };
+
+    return CorsLayer::new()
+        .allow_origin(origins(configured))
+        .allow_methods(config.cors_methods.clone())
+        .allow_headers(config.cors_headers.clone())
+        .allow_credentials(config.cors_credentials);
+}
+
+/// Removes the orphaned devices periodically, doubling the interval up to `MAX_REAP_BACKOFF` times
+/// while there's nothing to remove.
+async fn reap(state: Arc<State>, interval: Duration) {
+    let mut delay = interval;
+
+    loop {
+        time::sleep(delay).await;
+
+        let reaped = state.reap().await;
+
+        if reaped > 0 {
+            warn!(devices = reaped, "removed orphaned devices");
+
+            delay = interval;
+        } else {
+            delay = (delay * 2).min(interval * MAX_REAP_BACKOFF);
+        }
+    }
+}
+
+/// Builds the compression layer, skipping the small responses and the content types that aren't
+/// configured.
+fn compression(config: &Config) -> CompressionLayer<impl Predicate + use<>> {
+    let level = match config.compression_level {
+        Some(level) => CompressionLevel::Precise(level),
+        None => CompressionLevel::Default,
+    };
+
+    let types = config.compression_content_types.clone();
+
+    let compressible = move |_: http::StatusCode,
+                             _: http::Version,
+                             headers: &http::HeaderMap,
+                             _: &http::Extensions| {
+        if types.is_empty() {
+            return true;
+        }
+
+        let content_type = headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+
+        return types.iter().any(|prefix| content_type.starts_with(prefix.as_str()));
+    };
 
-pub async fn router<S: Sync + Send + 'static>(state: S) -> Router {
-    let state = Arc::new(state);
+    let predicate = SizeAbove::new(config.compression_min_size)
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES)
+        .and(NotForContentType::SSE)
+        .and(compressible);
 
+    return CompressionLayer::new()
+        .quality(level)
+        .compress_when(predicate);
+}
+
+pub async fn router(state: Arc<State>, config: &Config) -> Router {
+    // The request id is set before tracing so every request is logged with one.
     let service = ServiceBuilder::new()
+        .layer(SetRequestIdLayer::new(
+            config.request_id_header.clone(),
+            MakeRequestUuid,
+        ))
         .layer(TraceLayer::new_for_http())
-        .layer(CompressionLayer::new())
-        .layer(RequestBodyLimitLayer::new(REQUEST_BODY_LIMIT))
-        .layer(PropagateHeaderLayer::new(HeaderName::from_static(
-            "x-request-id",
-        )))
-        .layer(
-            // TODO: Receive `origins` as a router configuration.
-            CorsLayer::new()
-                .allow_origin([HeaderValue::from_str("https://televiu.fly.dev").unwrap()])
-                .allow_methods([http::Method::GET]),
-        );
+        .layer(RequestBodyLimitLayer::new(config.body_limit))
+        .layer(PropagateRequestIdLayer::new(
+            config.request_id_header.clone(),
+        ));
+
+    metrics::handle();
+
+    if !config.reap_interval.is_zero() {
+        tokio::spawn(reap(state.clone(), config.reap_interval));
+    }
+
+    let limiter = Arc::new(RateLimiter::new(
+        config.connections_per_minute,
+        config.trusted_proxies.clone(),
+    ));
+
+    if config.connections_per_minute > 0 {
+        tokio::spawn(limit::sweep(limiter.clone()));
+    }
+
+    let shared = cors(&[], config);
+
+    let paths = &config.routes;
+
+    let sockets = Router::new()
+        .route(
+            &paths.path(&paths.controller),
+            get(handlers::controller).layer(cors(&config.controller_origins, config)),
+        )
+        .route(
+            &paths.path(&paths.player),
+            get(handlers::player).layer(cors(&config.player_origins, config)),
+        )
+        .route(
+            &paths.path(&paths.admin),
+            get(handlers::admin).layer(shared.clone()),
+        )
+        .route_layer(middleware::from_fn_with_state(limiter, limit::rate_limit));
+
+    // Compressing the WebSocket upgrades is pointless, so only the HTTP routes are compressed.
+    let routes = Router::new()
+        .route(
+            &paths.path("/devices"),
+            get(handlers::devices).layer(shared.clone()),
+        )
+        .route(
+            &paths.path("/devices/{device}"),
+            delete(handlers::disconnect).layer(shared.clone()),
+        )
+        .route(&paths.path("/metrics"), get(metrics::render).layer(shared));
+
+    let routes = if config.compression_enabled {
+        routes.layer(compression(config))
+    } else {
+        routes
+    };
 
-    // TODO: Remove magic routes.
     let router = Router::new()
-        .route("/ws/controller", get(handlers::controller))
-        .route("/ws/player", get(handlers::player))
-        .layer(Extension(state))
+        .merge(sockets)
+        .merge(routes)
+        .layer(Extension(state.clone()))
+        .layer(Extension(Arc::new(config.clone())))
         .layer(service);
 
+    // Probes are merged after the layers so the CORS and body limit don't apply to them. The info
+    // route is public too, exposing nothing but counts and build information.
+    let probes = Router::new()
+        .route("/health", get(handlers::health))
+        .route("/ready", get(handlers::health))
+        .route("/info", get(handlers::info))
+        .layer(Extension(state));
+
+    let mut router = router.merge(probes);
+
+    // Added last so the probes are logged along with the other routes.
+    if let Some(access_log) = &config.access_log {
+        router = router.layer(middleware::from_fn_with_state(
+            access_log.clone(),
+            access::log,
+        ));
+    }
+
     return router;
 }
 
-pub struct Config {
-    pub host: String,
-    pub port: String,
+/// Waits for a SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = signal::ctrl_c().await {
+            error!(error = e.to_string(), "failed to listen for SIGINT");
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!(error = e.to_string(), "failed to listen for SIGTERM");
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
 }
 
-pub async fn listen(router: Router, config: Config) -> Result<(), Error> {
-    let addr = format!("{}:{}", config.host, config.port);
+/// Unpairs every registered device and waits, up to `timeout`, for their sessions to close.
+async fn drain(state: &State, timeout: Duration) {
+    let sessions = async {
+        // Players waiting to reconnect have no session to drain.
+        let mut parked = Vec::new();
+
+        for (device, channel) in state.channels.read().await.iter() {
+            if channel.read().await.parked.is_some() {
+                parked.push(device.clone());
+            }
+        }
+
+        for device in parked {
+            state.unregister_device(&device).await;
+        }
+
+        handlers::unpair_all(state).await;
+
+        while !state.channels.read().await.is_empty() {
+            time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+
+    match time::timeout(timeout, sessions).await {
+        Ok(_) => info!("all sessions drained"),
+        Err(_) => warn!("drain timeout elapsed with sessions still open"),
+    }
+}
+
+/// Binds a listener to the address.
+pub async fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    return bind_socket(addr, DEFAULT_BACKLOG, Duration::ZERO).await;
+}
+
+/// Binds a listener to the address, queuing up to `backlog` connections waiting to be accepted,
+/// with TCP keepalive probing the connections idle for `keepalive`, where zero disables it.
+///
+/// The accepted connections inherit the keepalive of the listener.
+pub async fn bind_socket(
+    addr: SocketAddr,
+    backlog: u32,
+    keepalive: Duration,
+) -> Result<TcpListener> {
+    let bind = || -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+        // As tokio does, so a restarted server can bind while its old connections linger.
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+
+        if !keepalive.is_zero() {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(keepalive))?;
+        }
+
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+
+        return TcpListener::from_std(socket.into());
+    };
+
+    return bind().map_err(|source| ServerError::Bind { addr, source });
+}
+
+/// Binds a listener to the address like [`bind_socket`], trying again up to `retries` times while
+/// the address is in use, as happens when a previous process is still releasing it.
+///
+/// The delay doubles after every attempt and is randomized between half and all of it, so
+/// instances restarting together don't retry in lockstep.
+pub async fn bind_with_retry(
+    addr: SocketAddr,
+    backlog: u32,
+    keepalive: Duration,
+    retries: u32,
+    delay: Duration,
+) -> Result<TcpListener> {
+    let mut attempt = 0;
+
+    loop {
+        let error = match bind_socket(addr, backlog, keepalive).await {
+            Ok(listener) => return Ok(listener),
+            Err(error) => error,
+        };
+
+        let in_use = matches!(
+            &error,
+            ServerError::Bind { source, .. } if source.kind() == io::ErrorKind::AddrInUse
+        );
+
+        if !in_use || attempt >= retries {
+            return Err(error);
+        }
+
+        attempt += 1;
+
+        let backoff = delay.saturating_mul(2u32.saturating_pow(attempt - 1).min(MAX_BIND_BACKOFF));
+        let wait = backoff.mul_f64(rand::rng().random_range(0.5..=1.0));
+
+        warn!(
+            address = addr.to_string(),
+            attempt = attempt,
+            retries = retries,
+            wait = wait.as_secs_f64(),
+            "address in use, retrying to bind",
+        );
+
+        time::sleep(wait).await;
+    }
+}
+
+pub async fn listen(router: Router, config: Config, state: Arc<State>) -> Result<()> {
+    // Every address the host resolves to is bound, so the server is reachable over both IPv4 and
+    // IPv6 when the host has both.
+    let mut addrs: Vec<SocketAddr> = lookup_host((config.host.as_str(), config.port))
+        .await?
+        .collect();
+
+    for addr in &config.addresses {
+        if !addrs.contains(addr) {
+            addrs.push(*addr);
+        }
+    }
+
+    let mut listeners = Vec::with_capacity(addrs.len());
+
+    for addr in addrs {
+        let listener = bind_with_retry(
+            addr,
+            config.backlog,
+            config.tcp_keepalive,
+            config.bind_retries,
+            config.bind_retry_delay,
+        )
+        .await?;
+
+        info!(address = addr.to_string(), "listening");
+
+        listeners.push(listener);
+    }
+
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let tls = RustlsConfig::from_pem_file(cert, key)
+                .await
+                .map_err(ServerError::Tls)?;
+
+            info!("serving over TLS");
+
+            return run_tls(
+                listeners,
+                router,
+                state,
+                config.drain_timeout,
+                config.handshake_timeout,
+                tls,
+                shutdown_signal(),
+            )
+            .await;
+        }
+        (None, None) => {}
+        _ => warn!("TLS needs both a certificate and a key, serving without TLS"),
+    }
+
+    return run(
+        listeners,
+        router,
+        state,
+        config.drain_timeout,
+        config.handshake_timeout,
+        shutdown_signal(),
+    )
+    .await;
+}
+
+/// Drains the sessions once `signal` completes, returning a receiver notified when they're drained.
+fn shutdown(
+    state: Arc<State>,
+    drain_timeout: Duration,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> watch::Receiver<bool> {
+    let (shutdown, drained) = watch::channel(false);
+
+    tokio::spawn(async move {
+        signal.await;
+
+        info!("shutdown signal received, draining sessions");
+
+        drain(&state, drain_timeout).await;
+
+        let _ = shutdown.send(true);
+    });
+
+    return drained;
+}
+
+/// Serves the router on the listeners until `signal` completes, then drains the sessions.
+///
+/// Connections that don't complete their handshake within `handshake_timeout` are closed.
+pub async fn run(
+    listeners: Vec<TcpListener>,
+    router: Router,
+    state: Arc<State>,
+    drain_timeout: Duration,
+    handshake_timeout: Duration,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+    let drained = shutdown(state, drain_timeout, signal);
+
+    let servers = listeners.into_iter().map(|listener| {
+        let mut drained = drained.clone();
+
+        // Tapping the listener gives the handlers the client address as connect info.
+        let listener = HandshakeListener::new(listener, handshake_timeout).tap_io(|_| {});
+
+        serve::serve(
+            listener,
+            router
+                .clone()
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(async move {
+            let _ = drained.wait_for(|drained| *drained).await;
+        })
+        .into_future()
+    });
+
+    try_join_all(servers).await?;
+
+    return Ok(());
+}
+
+/// Serves the router over TLS on the listeners until `signal` completes, then drains the sessions.
+///
+/// Connections that don't complete their handshake within `handshake_timeout` are closed.
+pub async fn run_tls(
+    listeners: Vec<TcpListener>,
+    router: Router,
+    state: Arc<State>,
+    drain_timeout: Duration,
+    handshake_timeout: Duration,
+    tls: RustlsConfig,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> Result<()> {
+    let mut drained = shutdown(state, drain_timeout, signal);
+
+    let handle = axum_server::Handle::new();
+
+    tokio::spawn({
+        let handle = handle.clone();
+
+        async move {
+            let _ = drained.wait_for(|drained| *drained).await;
+
+            handle.graceful_shutdown(None);
+        }
+    });
+
+    let mut servers = Vec::with_capacity(listeners.len());
+
+    for listener in listeners {
+        let server = axum_server::from_tcp_rustls(listener.into_std()?, tls.clone())
+            .map(|acceptor| HandshakeAcceptor::new(acceptor, handshake_timeout))
+            .handle(handle.clone())
+            .serve(
+                router
+                    .clone()
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            );
+
+        servers.push(server);
+    }
 
-    let listener = TcpListener::bind(addr).await?;
+    try_join_all(servers).await?;
 
-    return serve(listener, router).await;
+    return Ok(());
 }
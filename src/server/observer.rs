@@ -0,0 +1,30 @@
+use crate::server::protocol::Event;
+
+/// Side of a connection to the server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Peer {
+    Player,
+    Controller,
+}
+
+/// Hooks called on the lifecycle of the sessions, for embedders to react to them.
+///
+/// Every hook does nothing by default and runs on the session task, so it should return quickly.
+pub trait Observer: Send + Sync {
+    /// A player registered or resumed the device.
+    fn on_player_connected(&self, _device: &str) {}
+
+    /// A controller paired with the device.
+    fn on_controller_paired(&self, _device: &str) {}
+
+    /// A command from a controller was forwarded to the player of the device.
+    fn on_command(&self, _device: &str, _event: &Event) {}
+
+    /// A player or controller of the device disconnected.
+    fn on_disconnected(&self, _device: &str, _peer: Peer) {}
+}
+
+/// Observer ignoring every hook, used when the server isn't embedded.
+pub struct NoopObserver;
+
+impl Observer for NoopObserver {}
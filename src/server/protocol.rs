@@ -0,0 +1,411 @@
+use std::{fmt, str::FromStr};
+
+use axum::extract::ws::{CloseFrame, close_code};
+use serde::{Deserialize, Deserializer, Serialize, de};
+
+/// Version of the protocol spoken by the server.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Version of the shape of the events, upgraded by [`migrate`] when clients send older ones.
+///
+/// | Version | Shape                                                      |
+/// |---------|------------------------------------------------------------|
+/// | 1       | The data of the commands is carried in a `payload` string. |
+/// | 2       | Each command carries its data in its own typed fields.     |
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// WebSocket subprotocol matching the protocol version, negotiated with clients that ask for one.
+pub const SUBPROTOCOL: &str = "televiu.v1";
+
+/// Kind of the messages, without the data they carry, see [`Action`] for their meaning.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Command {
+    Pair,
+    Unpair,
+    Reset,
+    TransferTo,
+    Play,
+    Pause,
+    Resume,
+    Seek,
+    SetVolume,
+    Stop,
+    Capabilities,
+    Heartbeat,
+    Ping,
+    Ack,
+    Nack,
+    GetState,
+    State,
+    /// Identifies the [`ServerInfo`] message sent when a connection opens.
+    ServerInfo,
+}
+
+/// Commands controllers can send, as advertised in [`ServerInfo`].
+pub const CONTROLLER_COMMANDS: &[Command] = &[
+    Command::Pair,
+    Command::Unpair,
+    Command::Reset,
+    Command::TransferTo,
+    Command::Play,
+    Command::Pause,
+    Command::Resume,
+    Command::Seek,
+    Command::SetVolume,
+    Command::Stop,
+    Command::Capabilities,
+    Command::Heartbeat,
+    Command::Ping,
+    Command::GetState,
+];
+
+/// Description of the server, sent as the first message of every connection so clients can adapt
+/// to the version they're talking to.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerInfo {
+    /// Always `ServerInfo`, telling the message apart from the events.
+    pub command: Command,
+    pub server_version: String,
+    pub protocol_version: u32,
+    pub commands: Vec<Command>,
+}
+
+impl ServerInfo {
+    pub fn new() -> Self {
+        Self {
+            command: Command::ServerInfo,
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            commands: CONTROLLER_COMMANDS.to_vec(),
+        }
+    }
+}
+
+/// Deserializes a number, which old clients send as a string.
+fn number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + FromStr,
+    T::Err: fmt::Display,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Number<T> {
+        Number(T),
+        String(String),
+    }
+
+    return match Number::<T>::deserialize(deserializer)? {
+        Number::Number(number) => Ok(number),
+        Number::String(string) => string.trim().parse().map_err(de::Error::custom),
+    };
+}
+
+/// Command along with the data it carries, tagged by the `command` field, as in
+/// `{ "command": "SetVolume", "level": 30 }`.
+///
+/// Clients written before the commands were typed put their data in a `payload` string, which is
+/// still accepted in place of the field of the command.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "command")]
+pub enum Action {
+    Pair {
+        /// Device paired with, only set in the confirmation sent back to the controller.
+        #[serde(default, alias = "payload", skip_serializing_if = "Option::is_none")]
+        device: Option<String>,
+    },
+    Unpair,
+    /// Unpairs the controller without disconnecting it, so it can pair again from a clean state.
+    ///
+    /// The player is sent an `Unpair` but stays registered.
+    Reset,
+    /// Offers the control of the device to the controller connecting with the one-time token in
+    /// place of the secret, which takes over without pairing again and detaches the sender.
+    TransferTo {
+        #[serde(alias = "payload")]
+        token: String,
+    },
+    Play {
+        #[serde(alias = "payload")]
+        url: String,
+    },
+    Pause,
+    Resume,
+    /// Jumps to the position, in seconds.
+    Seek {
+        #[serde(alias = "payload", deserialize_with = "number")]
+        position: f64,
+    },
+    /// Sets the volume to the level, from 0 to 100.
+    SetVolume {
+        #[serde(alias = "payload", deserialize_with = "number")]
+        level: u8,
+    },
+    Stop,
+    /// Asks the player for its capabilities, which it answers with the same command carrying a
+    /// JSON description of them.
+    Capabilities {
+        #[serde(default, alias = "payload", skip_serializing_if = "Option::is_none")]
+        capabilities: Option<String>,
+    },
+    /// Checks the relay is alive end to end, echoed back by the player along with its payload.
+    Heartbeat {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        payload: Option<String>,
+    },
+    /// Sent by the server to keep idle connections open, which clients ignore or echo back.
+    Ping,
+    /// Sent by the player when it carried out the command with the same id.
+    Ack,
+    /// Sent by the player when it can't carry out the command with the same id.
+    Nack {
+        #[serde(default, alias = "payload", skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+    /// Asks for the playback state, which the server answers with a `State` event.
+    ///
+    /// The server's state is authoritative: it's the one the commands are validated against, and
+    /// players don't have to report theirs.
+    GetState,
+    /// Answer to `GetState`, carrying the name of the controller state.
+    State {
+        #[serde(alias = "payload")]
+        state: String,
+    },
+}
+
+impl Action {
+    pub fn command(&self) -> Command {
+        return match self {
+            Action::Pair { .. } => Command::Pair,
+            Action::Unpair => Command::Unpair,
+            Action::Reset => Command::Reset,
+            Action::TransferTo { .. } => Command::TransferTo,
+            Action::Play { .. } => Command::Play,
+            Action::Pause => Command::Pause,
+            Action::Resume => Command::Resume,
+            Action::Seek { .. } => Command::Seek,
+            Action::SetVolume { .. } => Command::SetVolume,
+            Action::Stop => Command::Stop,
+            Action::Capabilities { .. } => Command::Capabilities,
+            Action::Heartbeat { .. } => Command::Heartbeat,
+            Action::Ping => Command::Ping,
+            Action::Ack => Command::Ack,
+            Action::Nack { .. } => Command::Nack,
+            Action::GetState => Command::GetState,
+            Action::State { .. } => Command::State,
+        };
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    #[serde(flatten)]
+    pub action: Action,
+    /// Identifier chosen by the sender, echoed back in the replies to the event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Version of the shape of the event, only sent when it isn't the current one.
+    #[serde(default = "current_schema", skip_serializing_if = "is_current_schema")]
+    pub schema_version: u32,
+    /// Number of the event in the session of the controller, checked when
+    /// [`Config::enforce_seq`](crate::server::Config::enforce_seq) is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+fn current_schema() -> u32 {
+    return SCHEMA_VERSION;
+}
+
+fn is_current_schema(version: &u32) -> bool {
+    return *version == SCHEMA_VERSION;
+}
+
+impl Event {
+    pub fn new(action: Action) -> Self {
+        Self {
+            action,
+            id: None,
+            schema_version: SCHEMA_VERSION,
+            seq: None,
+        }
+    }
+
+    pub fn command(&self) -> Command {
+        return self.action.command();
+    }
+}
+
+impl ToString for Event {
+    fn to_string(&self) -> String {
+        return format!("{:?}", self.action);
+    }
+}
+
+fn default_version() -> u32 {
+    return PROTOCOL_VERSION;
+}
+
+/// Message exchanged over the WebSocket connections.
+///
+/// Messages without a version are assumed to use the current one, so clients written before the
+/// envelope existed keep working.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Envelope {
+    #[serde(default = "default_version")]
+    pub version: u32,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl Envelope {
+    pub fn new(event: Event) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            event,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The message isn't a valid envelope.
+    Malformed(serde_json::Error),
+    /// The message uses a version the server doesn't speak.
+    UnsupportedVersion(u32),
+    /// The event has a shape newer than the server knows.
+    UnsupportedSchema(u32),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            ProtocolError::Malformed(e) => write!(f, "malformed message: {}", e),
+            ProtocolError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            ProtocolError::UnsupportedSchema(version) => {
+                write!(f, "unsupported schema version {}", version)
+            }
+        };
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Upgrades an event of an older schema to the current one.
+///
+/// The fields of [`Action`] take the `payload` of version 1 as an alias, so those events are
+/// already parsed into the current shape and only need their version bumped.
+pub fn migrate(mut event: Event) -> Event {
+    if event.schema_version < 2 {
+        event.schema_version = 2;
+    }
+
+    return event;
+}
+
+/// Parses a message received from a WebSocket connection, upgrading its event to the current
+/// schema.
+pub fn parse_message(text: &str) -> Result<Envelope, ProtocolError> {
+    #[derive(Deserialize)]
+    struct Schema {
+        #[serde(default = "current_schema")]
+        schema_version: u32,
+    }
+
+    // The version is checked first, since newer events may not parse at all.
+    let schema: Schema = serde_json::from_str(text).map_err(ProtocolError::Malformed)?;
+
+    if schema.schema_version > SCHEMA_VERSION {
+        return Err(ProtocolError::UnsupportedSchema(schema.schema_version));
+    }
+
+    let mut envelope: Envelope = serde_json::from_str(text).map_err(ProtocolError::Malformed)?;
+
+    if envelope.version != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(envelope.version));
+    }
+
+    envelope.event = migrate(envelope.event);
+
+    return Ok(envelope);
+}
+
+/// Reasons for the server to close a WebSocket connection.
+///
+/// Each reason is sent to the client as the code and reason of the close frame:
+///
+/// | Reason              | Code | Meaning                                        |
+/// |---------------------|------|------------------------------------------------|
+/// | `ProtocolError`     | 1002 | The message or command sequence is invalid.    |
+/// | `InvalidSecret`     | 1008 | The secret doesn't match the device's secret.  |
+/// | `Transferred`       | 1000 | The control was handed over to a controller.   |
+/// | `InternalError`     | 1011 | The server failed to handle the connection.    |
+/// | `Backpressure`      | 1013 | The player can't keep up with the commands.    |
+/// | `TooManyDevices`    | 1013 | The server can't register more devices.        |
+/// | `SessionExpired`    | 4001 | The session lasted longer than allowed.        |
+/// | `DeviceNotFound`    | 4004 | No player is registered with the given device. |
+/// | `IdleTimeout`       | 4008 | The controller didn't send anything in time.   |
+/// | `AlreadyPaired`     | 4009 | The controller tried to pair more than once.   |
+/// | `CodeTaken`         | 4009 | The pairing code requested is already taken.   |
+/// | `UnsupportedSchema` | 4015 | The event is newer than the server supports.   |
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+    Transferred,
+    ProtocolError,
+    InvalidSecret,
+    InternalError,
+    Backpressure,
+    TooManyDevices,
+    SessionExpired,
+    DeviceNotFound,
+    IdleTimeout,
+    AlreadyPaired,
+    CodeTaken,
+    UnsupportedSchema,
+}
+
+impl CloseReason {
+    pub fn code(&self) -> u16 {
+        return match self {
+            CloseReason::Transferred => close_code::NORMAL,
+            CloseReason::ProtocolError => close_code::PROTOCOL,
+            CloseReason::InvalidSecret => close_code::POLICY,
+            CloseReason::InternalError => close_code::ERROR,
+            CloseReason::Backpressure => close_code::AGAIN,
+            CloseReason::TooManyDevices => close_code::AGAIN,
+            CloseReason::SessionExpired => 4001,
+            CloseReason::DeviceNotFound => 4004,
+            CloseReason::IdleTimeout => 4008,
+            CloseReason::AlreadyPaired => 4009,
+            CloseReason::CodeTaken => 4009,
+            CloseReason::UnsupportedSchema => 4015,
+        };
+    }
+
+    pub fn reason(&self) -> &'static str {
+        return match self {
+            CloseReason::Transferred => "control transferred",
+            CloseReason::ProtocolError => "protocol error",
+            CloseReason::InvalidSecret => "invalid secret",
+            CloseReason::InternalError => "internal error",
+            CloseReason::Backpressure => "too many commands",
+            CloseReason::TooManyDevices => "too many devices",
+            CloseReason::SessionExpired => "session expired",
+            CloseReason::DeviceNotFound => "device not found",
+            CloseReason::IdleTimeout => "idle timeout",
+            CloseReason::AlreadyPaired => "already paired",
+            CloseReason::CodeTaken => "pairing code taken",
+            CloseReason::UnsupportedSchema => "unsupported schema version",
+        };
+    }
+
+    pub fn frame(&self) -> CloseFrame {
+        return CloseFrame {
+            code: self.code(),
+            reason: self.reason().into(),
+        };
+    }
+}
@@ -0,0 +1,185 @@
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+
+use crate::server::protocol::{Command, Event, migrate};
+
+/// WebSocket subprotocol of controllers exchanging JSON-RPC 2.0 messages instead of events.
+pub const JSONRPC_SUBPROTOCOL: &str = "televiu.jsonrpc";
+
+/// The request isn't valid JSON.
+pub const PARSE_ERROR: i64 = -32700;
+/// The request isn't a valid JSON-RPC request object.
+pub const INVALID_REQUEST: i64 = -32600;
+/// No command has the name of the method.
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// The params don't fit the command of the method.
+pub const INVALID_PARAMS: i64 = -32602;
+/// The player, or the server on its behalf, refused the command.
+pub const COMMAND_FAILED: i64 = -32000;
+
+/// Shape of the messages a controller exchanges with the server.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Framing {
+    /// The events of [`protocol`](crate::server::protocol), as they're forwarded to the players.
+    #[default]
+    Native,
+    /// JSON-RPC 2.0 envelopes, where the methods are the commands in snake case, as in `"pair"`
+    /// or `"set_volume"`, and the params the fields of the command.
+    JsonRpc,
+}
+
+/// Error object of a JSON-RPC response.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+/// Request that can't be turned into an event, answered with an error response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    /// Id of the request, `null` when it couldn't be read.
+    pub id: Value,
+    pub error: RpcError,
+}
+
+impl Rejection {
+    fn new(id: Value, code: i64, message: impl Into<String>) -> Self {
+        Self {
+            id,
+            error: RpcError {
+                code,
+                message: message.into(),
+            },
+        }
+    }
+
+    /// Serializes the error response for the controller.
+    pub fn response(&self) -> String {
+        return json!({ "jsonrpc": "2.0", "error": self.error, "id": self.id }).to_string();
+    }
+}
+
+/// Converts a command name to its method, as in `SetVolume` to `set_volume`.
+fn method(command: &str) -> String {
+    let mut method = String::with_capacity(command.len() + 4);
+
+    for (i, c) in command.chars().enumerate() {
+        if c.is_ascii_uppercase() && i > 0 {
+            method.push('_');
+        }
+
+        method.push(c.to_ascii_lowercase());
+    }
+
+    return method;
+}
+
+/// Converts a method to the name of its command, as in `set_volume` to `SetVolume`.
+fn command(method: &str) -> String {
+    return method
+        .split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+
+            return match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            };
+        })
+        .collect();
+}
+
+/// Parses a JSON-RPC request of a controller into the event it stands for.
+///
+/// The id of the request becomes the id of the event in its JSON form, so `1` and `"1"` stay
+/// apart when the player's replies are turned back into responses. Notifications, which have no
+/// id, get no response.
+pub fn parse_request(text: &str) -> Result<Event, Rejection> {
+    let request: Value = serde_json::from_str(text)
+        .map_err(|e| Rejection::new(Value::Null, PARSE_ERROR, e.to_string()))?;
+
+    let Value::Object(mut request) = request else {
+        return Err(Rejection::new(Value::Null, INVALID_REQUEST, "request isn't an object"));
+    };
+
+    let id = request.remove("id").unwrap_or(Value::Null);
+
+    if request.get("jsonrpc") != Some(&json!("2.0")) {
+        return Err(Rejection::new(id, INVALID_REQUEST, "jsonrpc must be \"2.0\""));
+    }
+
+    let Some(Value::String(method)) = request.remove("method") else {
+        return Err(Rejection::new(id, INVALID_REQUEST, "missing method"));
+    };
+
+    let mut fields = match request.remove("params") {
+        Some(Value::Object(params)) => params,
+        None | Some(Value::Null) => Map::new(),
+        Some(_) => return Err(Rejection::new(id, INVALID_PARAMS, "params must be an object")),
+    };
+
+    let name = Value::String(command(&method));
+
+    if serde_json::from_value::<Command>(name.clone()).is_err() {
+        return Err(Rejection::new(id, METHOD_NOT_FOUND, format!("unknown method {}", method)));
+    }
+
+    // The params can't smuggle in another command or id than the ones of the envelope.
+    fields.insert("command".to_string(), name);
+    fields.remove("id");
+
+    if !id.is_null() {
+        fields.insert("id".to_string(), Value::String(id.to_string()));
+    }
+
+    return match serde_json::from_value::<Event>(Value::Object(fields)) {
+        Ok(event) => Ok(migrate(event)),
+        Err(e) => Err(Rejection::new(id, INVALID_PARAMS, e.to_string())),
+    };
+}
+
+/// Turns a message for a controller into its JSON-RPC form.
+///
+/// Messages answering a request, which carry its id, become its response: a `Nack` is an error
+/// and anything else the result, holding the fields of the command. The others, like the server
+/// info or the events of the player, become notifications.
+pub fn to_rpc(text: &str) -> String {
+    let Ok(Value::Object(mut fields)) = serde_json::from_str::<Value>(text) else {
+        return text.to_string();
+    };
+
+    let command = match fields.remove("command") {
+        Some(Value::String(command)) => command,
+        _ => return text.to_string(),
+    };
+
+    // Only the server and the player care about the versions and the order of the events.
+    fields.remove("version");
+    fields.remove("schema_version");
+    fields.remove("seq");
+
+    let Some(Value::String(id)) = fields.remove("id") else {
+        return json!({ "jsonrpc": "2.0", "method": method(&command), "params": fields })
+            .to_string();
+    };
+
+    // Ids not set through a JSON-RPC request, like the ones of other controllers, stay strings.
+    let id = serde_json::from_str::<Value>(&id).unwrap_or(Value::String(id));
+
+    if command == "Nack" {
+        let message = match fields.remove("reason") {
+            Some(Value::String(reason)) => reason,
+            _ => "command failed".to_string(),
+        };
+
+        let error = RpcError {
+            code: COMMAND_FAILED,
+            message,
+        };
+
+        return json!({ "jsonrpc": "2.0", "error": error, "id": id }).to_string();
+    }
+
+    return json!({ "jsonrpc": "2.0", "result": fields, "id": id }).to_string();
+}
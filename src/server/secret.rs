@@ -0,0 +1,72 @@
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serializer};
+use subtle::ConstantTimeEq;
+
+/// Secret a controller presents to take control of a device.
+///
+/// The value is redacted when formatted and isn't serializable, so it can't end up in logs or
+/// responses by accident. The registration sent to the player is the only place exposing it, see
+/// [`exposed`].
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Generates a random secret.
+    pub fn generate() -> Self {
+        return Self(uuid::Uuid::new_v4().simple().to_string());
+    }
+
+    /// Returns the value of the secret, which must not be logged.
+    pub fn expose(&self) -> &str {
+        return &self.0;
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        return Self(value);
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        return Self(value.to_string());
+    }
+}
+
+/// Compares the secrets in constant time, so the time taken doesn't leak how much of a guess
+/// matches.
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        return self.0.as_bytes().ct_eq(other.0.as_bytes()).into();
+    }
+}
+
+impl Eq for Secret {}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "***");
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "***");
+    }
+}
+
+/// Serializes the secret with its value, for `#[serde(with = "exposed")]` on the fields meant to
+/// carry it.
+pub mod exposed {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(secret: &Secret, serializer: S) -> Result<S::Ok, S::Error> {
+        return serializer.serialize_str(&secret.0);
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Secret, D::Error> {
+        return String::deserialize(deserializer).map(Secret);
+    }
+}
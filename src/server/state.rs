@@ -1,22 +1,477 @@
-use std::collections::HashMap;
+use std::{
+    collections::{
+        HashMap,
+        hash_map::{Entry, VacantEntry},
+    },
+    sync::Arc,
+    time::SystemTime,
+};
 
 use axum::extract::ws::Utf8Bytes;
-use tokio::sync::{RwLock, mpsc};
+use tokio::{
+    runtime::Handle,
+    sync::{RwLock, broadcast, mpsc, oneshot},
+    time::Instant,
+};
 
-pub struct Channel {
-    pub sender: Option<mpsc::Sender<Utf8Bytes>>,
+use serde::Serialize;
+use tracing::warn;
+
+use crate::server::{
+    auth::{AuthProvider, MemoryAuthProvider},
+    config::IdFormat,
+    limit::TokenBucket,
+    observer::{NoopObserver, Observer},
+    protocol::{Command, Event},
+    secret::Secret,
+    state_machine::ControllerState,
+};
+
+/// Events kept for slow subscribers of the state changes before they start missing some.
+const EVENTS_CAPACITY: usize = 256;
+
+/// Connection to a registered player, carrying messages of type `T`, which are events by default.
+///
+/// The sender is cloned for every controller attached to the device, so several controllers can
+/// drive the same player. Their commands are queued on the same channel and the player applies
+/// them in the order the server received them, meaning the last command wins on conflicts.
+pub struct Channel<T = Event> {
+    pub sender: mpsc::Sender<T>,
+    /// Secret issued for the device, given back to its player when it resumes.
+    pub secret: Secret,
+    /// Number of controllers attached to the device.
+    pub controllers: usize,
+    /// Token the player presents to resume the device after a disconnection.
+    pub token: String,
+    /// Receiver of a disconnected player and when it disconnected, kept while it may reconnect.
+    pub parked: Option<(Instant, mpsc::Receiver<T>)>,
+    /// State the last controller left the device in, restored by resuming controllers.
+    pub state: ControllerState,
+    /// Replies from the player, delivered to every attached controller.
+    pub replies: broadcast::Sender<Utf8Bytes>,
+    /// Commands the device still accepts from its controllers, shared between them.
+    pub bucket: TokenBucket,
+    /// When the player registered the device, or last resumed it.
+    pub connected_at: SystemTime,
+    /// Control of the device offered by one of its controllers, until another takes it over.
+    pub handoff: Option<Handoff>,
 }
 
-type Device = String;
+/// Control of a device offered by its controller to the one presenting the token.
+pub struct Handoff {
+    pub token: Secret,
+    /// Tells the offering controller to detach, giving it where to send the state it leaves the
+    /// device in.
+    pub detach: oneshot::Sender<oneshot::Sender<ControllerState>>,
+}
+
+pub type Device = String;
+
+/// Device added by [`State::register_device`], with what its player needs to run its session.
+pub struct Registered<T = Event> {
+    pub device: Device,
+    pub secret: Secret,
+    /// Token the player presents to resume the device after a disconnection.
+    pub token: String,
+    /// Receiver for the commands sent to the player.
+    pub receiver: mpsc::Receiver<T>,
+    /// Sender for the player replies.
+    pub replies: broadcast::Sender<Utf8Bytes>,
+}
+
+/// Copy of what can be shown of a registered device, taken by [`State::snapshot`].
+///
+/// It leaves out the secret and the token of the device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub device: Device,
+    /// Whether a controller is attached to the device, which it may not have paired with yet.
+    pub attached: bool,
+    /// When the player registered the device, or last resumed it.
+    pub connected_at: SystemTime,
+    /// Whether the player is disconnected and may still resume the device.
+    pub parked: bool,
+}
+
+/// Reason for [`State::register_device_as`] to refuse a device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegisterError {
+    /// `max_devices` are already registered.
+    Full,
+    /// Another device is registered under the requested name.
+    Taken,
+    /// The requested name isn't a short id, see [`IdFormat::is_short`].
+    InvalidCode,
+}
+
+/// Change in the state of the server, streamed to administrators.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StateEvent {
+    DeviceRegistered { device: Device },
+    ControllerPaired { device: Device },
+    CommandForwarded { device: Device, command: Command },
+    DeviceRemoved { device: Device },
+}
 
-pub struct State {
-    pub channels: RwLock<HashMap<Device, RwLock<Channel>>>,
+/// Devices registered with the server and their channels, carrying messages of type `T`, which
+/// are events by default.
+pub struct State<T = Event> {
+    pub channels: RwLock<HashMap<Device, RwLock<Channel<T>>>>,
+    /// Bus of the state changes, which nobody may be listening to.
+    pub events: broadcast::Sender<StateEvent>,
+    /// Hooks called on the lifecycle of the sessions.
+    pub observer: Arc<dyn Observer>,
+    /// Issues and verifies the secrets of the devices.
+    pub auth: Arc<dyn AuthProvider>,
+    /// When the server started.
+    pub started: Instant,
+    /// Signal telling the sessions to stop, fired by [`State::shutdown`].
+    pub stop: broadcast::Sender<()>,
 }
 
 impl State {
     pub fn new() -> Self {
+        return Self::default();
+    }
+}
+
+impl<T> Default for State<T> {
+    fn default() -> Self {
+        return Self::with_observer(Arc::new(NoopObserver));
+    }
+}
+
+impl<T> State<T> {
+    /// Creates the state with hooks called on the lifecycle of the sessions.
+    pub fn with_observer(observer: Arc<dyn Observer>) -> Self {
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        let (stop, _) = broadcast::channel(1);
+
         Self {
             channels: RwLock::new(HashMap::new()),
+            events,
+            observer,
+            auth: Arc::new(MemoryAuthProvider::default()),
+            started: Instant::now(),
+            stop,
         }
     }
+
+    /// Creates the state with a provider issuing and verifying the secrets of the devices.
+    pub fn with_auth(auth: Arc<dyn AuthProvider>) -> Self {
+        return Self {
+            auth,
+            ..Self::default()
+        };
+    }
+
+    /// Tells the player and controller sessions to stop, which removes their devices.
+    ///
+    /// Only the sessions running when it's called are stopped.
+    pub fn shutdown(&self) {
+        let _ = self.stop.send(());
+    }
+
+    /// Publishes the state change to its current subscribers, if any.
+    pub fn publish(&self, event: StateEvent) {
+        let _ = self.events.send(event);
+    }
+
+    /// Registers a new device whose channel queues up to `capacity` commands, or returns `None` when
+    /// `max_devices` are already registered.
+    ///
+    /// The device is inserted under the write lock and never replaces an existing one, and its
+    /// secret is issued once the lock is released.
+    pub async fn register_device(
+        &self,
+        capacity: usize,
+        max_devices: usize,
+        id_format: IdFormat,
+    ) -> Option<Registered<T>> {
+        return self
+            .register_device_with(capacity, max_devices, || id_format.generate())
+            .await;
+    }
+
+    /// Registers a new device like [`State::register_device`], naming it with `generate`, which is
+    /// called again until it returns a name that isn't taken.
+    pub async fn register_device_with(
+        &self,
+        capacity: usize,
+        max_devices: usize,
+        mut generate: impl FnMut() -> Device,
+    ) -> Option<Registered<T>> {
+        let reserved = {
+            let mut channels = self.channels.write().await;
+
+            if channels.len() >= max_devices {
+                return None;
+            }
+
+            loop {
+                let device = generate();
+
+                match channels.entry(device.clone()) {
+                    Entry::Vacant(entry) => break self.reserve(entry, device, capacity),
+                    Entry::Occupied(_) => {
+                        // Overwriting the entry would hand the session of another player over.
+                        warn!(device = device, "generated device id is taken, generating another");
+                    }
+                }
+            }
+        };
+
+        return Some(self.issue(reserved).await);
+    }
+
+    /// Registers a new device under the given name, like a pairing code the player displays before
+    /// any controller connects, unless another device has it.
+    ///
+    /// The name must be a short id, so a player can't pick one no controller would type.
+    pub async fn register_device_as(
+        &self,
+        capacity: usize,
+        max_devices: usize,
+        device: Device,
+    ) -> Result<Registered<T>, RegisterError> {
+        if !IdFormat::is_short(&device) {
+            return Err(RegisterError::InvalidCode);
+        }
+
+        let reserved = {
+            let mut channels = self.channels.write().await;
+
+            if channels.len() >= max_devices {
+                return Err(RegisterError::Full);
+            }
+
+            match channels.entry(device.clone()) {
+                Entry::Vacant(entry) => self.reserve(entry, device, capacity),
+                Entry::Occupied(_) => return Err(RegisterError::Taken),
+            }
+        };
+
+        return Ok(self.issue(reserved).await);
+    }
+
+    /// Inserts the channel of a new device in the vacant entry of the registry, reserving its name
+    /// until [`State::issue`] gives it a secret.
+    ///
+    /// The secret of the channel is a placeholder until then, which no controller can attach with
+    /// since they're verified by the provider.
+    fn reserve(
+        &self,
+        entry: VacantEntry<'_, Device, RwLock<Channel<T>>>,
+        device: Device,
+        capacity: usize,
+    ) -> Registered<T> {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let (replies, _) = broadcast::channel(16);
+
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let secret = Secret::generate();
+
+        entry.insert(RwLock::new(Channel {
+            sender,
+            secret: secret.clone(),
+            controllers: 0,
+            token: token.clone(),
+            parked: None,
+            state: ControllerState::default(),
+            replies: replies.clone(),
+            bucket: TokenBucket::default(),
+            connected_at: SystemTime::now(),
+            handoff: None,
+        }));
+
+        return Registered {
+            device,
+            secret,
+            token,
+            receiver,
+            replies,
+        };
+    }
+
+    /// Issues the secret of a reserved device, without holding the lock of the registry since the
+    /// provider may be slow, like one backed by a remote store.
+    async fn issue(&self, mut registered: Registered<T>) -> Registered<T> {
+        let secret = self.auth.issue(&registered.device).await;
+
+        let (issued, vacant) = match self.channels.read().await.get(&registered.device) {
+            Some(channel) => {
+                let mut lock = channel.write().await;
+                let issued = lock.token == registered.token;
+
+                if issued {
+                    lock.secret = secret.clone();
+                }
+
+                (issued, false)
+            }
+            None => (false, true),
+        };
+
+        if issued {
+            self.publish(StateEvent::DeviceRegistered {
+                device: registered.device.clone(),
+            });
+        } else if vacant {
+            // Removed while its secret was issued, after the removal revoked what it had.
+            self.auth.revoke(&registered.device).await;
+        }
+
+        registered.secret = secret;
+
+        return registered;
+    }
+
+    /// Copies the registered devices out of the registry, so they can be used without holding its
+    /// lock.
+    pub async fn snapshot(&self) -> Vec<DeviceInfo> {
+        let channels = self.channels.read().await;
+        let mut devices = Vec::with_capacity(channels.len());
+
+        for (device, channel) in channels.iter() {
+            let lock = channel.read().await;
+
+            devices.push(DeviceInfo {
+                device: device.clone(),
+                attached: lock.controllers > 0,
+                connected_at: lock.connected_at,
+                parked: lock.parked.is_some(),
+            });
+        }
+
+        return devices;
+    }
+
+    /// Removes the device, returning whether it was registered.
+    ///
+    /// Removing a device that isn't registered, or no longer is, does nothing.
+    pub async fn unregister_device(&self, device: &str) -> bool {
+        if self.channels.write().await.remove(device).is_none() {
+            return false;
+        }
+
+        self.auth.revoke(device).await;
+
+        self.publish(StateEvent::DeviceRemoved {
+            device: device.to_string(),
+        });
+
+        return true;
+    }
+
+    /// Removes the device if it's still the one registered with the token, returning whether it
+    /// was removed.
+    ///
+    /// Unlike [`State::unregister_device`], it leaves alone a device registered again under the
+    /// same name after the one of the token was removed.
+    pub async fn unregister_session(&self, device: &str, token: &str) -> bool {
+        let mut channels = self.channels.write().await;
+
+        let registered = match channels.get(device) {
+            Some(channel) => channel.read().await.token == token,
+            None => false,
+        };
+
+        if !registered {
+            return false;
+        }
+
+        channels.remove(device);
+        drop(channels);
+
+        self.auth.revoke(device).await;
+
+        self.publish(StateEvent::DeviceRemoved {
+            device: device.to_string(),
+        });
+
+        return true;
+    }
+
+    /// Removes the devices whose player went away without being parked, which happens when its
+    /// session ends abnormally, returning how many were removed.
+    pub async fn reap(&self) -> usize {
+        let mut channels = self.channels.write().await;
+        let mut reaped = Vec::new();
+
+        channels.retain(|device, channel| {
+            let orphaned = channel.get_mut().sender.is_closed();
+
+            if orphaned {
+                reaped.push(device.clone());
+            }
+
+            !orphaned
+        });
+
+        drop(channels);
+
+        let count = reaped.len();
+
+        for device in reaped {
+            self.auth.revoke(&device).await;
+            self.publish(StateEvent::DeviceRemoved { device });
+        }
+
+        return count;
+    }
+}
+
+/// Removes the device of a session when dropped, so it doesn't outlive a session task that was
+/// aborted or panicked.
+///
+/// Sessions that clean up after themselves, or park the device for their player to reconnect,
+/// disarm it on the way out.
+pub struct DeviceGuard<T: Send + 'static = Event> {
+    state: Arc<State<T>>,
+    device: Device,
+    token: String,
+    armed: bool,
+}
+
+impl<T: Send + 'static> DeviceGuard<T> {
+    /// Guards the device registered with the token.
+    pub fn new(state: Arc<State<T>>, device: Device, token: String) -> Self {
+        return Self {
+            state,
+            device,
+            token,
+            armed: true,
+        };
+    }
+
+    /// Leaves the device registered when the guard is dropped.
+    pub fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl<T: Send + 'static> Drop for DeviceGuard<T> {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        // Dropping can't wait for the locks, so the device is removed by a task of its own.
+        let Ok(runtime) = Handle::try_current() else {
+            warn!(device = self.device, "no runtime to remove the device of a dropped session");
+
+            return;
+        };
+
+        let state = self.state.clone();
+        let device = std::mem::take(&mut self.device);
+        let token = std::mem::take(&mut self.token);
+
+        runtime.spawn(async move {
+            if state.unregister_session(&device, &token).await {
+                warn!(device = device, "device of an interrupted session removed");
+            }
+        });
+    }
 }
@@ -0,0 +1,181 @@
+use std::fmt;
+
+use crate::server::protocol::Command;
+
+/// State of a controller session, driven by the commands it sends.
+///
+/// | Command        | Valid from                      | Moves to   |
+/// |----------------|---------------------------------|------------|
+/// | `Pair`         | `Unpaired`                      | `Paired`   |
+/// | `Play`         | `Paired`, `Stopped`             | `Played`   |
+/// | `Pause`        | `Played`                        | `Paused`   |
+/// | `Resume`       | `Paused`                        | `Played`   |
+/// | `Stop`         | `Played`, `Paused`              | `Stopped`  |
+/// | `Seek`         | `Played`, `Paused`              | unchanged  |
+/// | `SetVolume`    | `Paired`, `Played`, `Paused`    | unchanged  |
+/// | `Capabilities` | any but `Unpaired`              | unchanged  |
+/// | `Heartbeat`    | any                             | unchanged  |
+/// | `Ping`         | any                             | unchanged  |
+/// | `GetState`     | any                             | unchanged  |
+/// | `Unpair`       | any but `Unpaired`              | `Unpaired` |
+/// | `Reset`        | any                             | `Unpaired` |
+/// | `TransferTo`   | any but `Unpaired`              | unchanged  |
+/// | `Ack`, `Nack`  | never, only players send them   | unchanged  |
+/// | `State`        | never, only the server sends it | unchanged  |
+/// | `ServerInfo`   | never, only the server sends it | unchanged  |
+///
+/// An invalid transition leaves the state unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ControllerState {
+    #[default]
+    Unpaired,
+    Paired,
+    Played,
+    Paused,
+    Stopped,
+}
+
+/// A command that isn't valid in the state the controller is in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransitionError {
+    pub from: ControllerState,
+    pub command: Command,
+}
+
+impl fmt::Display for TransitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "cannot {:?} while {:?}", self.command, self.from);
+    }
+}
+
+impl std::error::Error for TransitionError {}
+
+impl ControllerState {
+    /// Returns the state reached by applying the command, if the transition is valid.
+    fn next(&self, command: &Command) -> Option<ControllerState> {
+        use ControllerState::*;
+
+        return match (command, *self) {
+            (Command::Pair, Unpaired) => Some(Paired),
+            (Command::Play, Paired | Stopped) => Some(Played),
+            (Command::Pause, Played) => Some(Paused),
+            (Command::Resume, Paused) => Some(Played),
+            (Command::Stop, Played | Paused) => Some(Stopped),
+            (Command::Seek, Played | Paused) => Some(*self),
+            (Command::SetVolume, Paired | Played | Paused) => Some(*self),
+            (Command::Capabilities, Paired | Played | Paused | Stopped) => Some(*self),
+            (Command::Heartbeat, _) => Some(*self),
+            (Command::Ping, _) => Some(*self),
+            (Command::GetState, _) => Some(*self),
+            (Command::Unpair, Paired | Played | Paused | Stopped) => Some(Unpaired),
+            (Command::Reset, _) => Some(Unpaired),
+            (Command::TransferTo, Paired | Played | Paused | Stopped) => Some(*self),
+            _ => None,
+        };
+    }
+
+    pub fn transition(&mut self, command: Command) -> Result<(), TransitionError> {
+        match self.next(&command) {
+            Some(next) => {
+                *self = next;
+
+                return Ok(());
+            }
+            None => {
+                return Err(TransitionError {
+                    from: *self,
+                    command,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ControllerState::{self, *};
+    use crate::server::protocol::Command;
+
+    const STATES: [ControllerState; 5] = [Unpaired, Paired, Played, Paused, Stopped];
+
+    /// The table of [`ControllerState`], with `None` where the command is rejected.
+    fn expected(state: ControllerState, command: Command) -> Option<ControllerState> {
+        let [unpaired, paired, played, paused, stopped] = match command {
+            Command::Pair => [Some(Paired), None, None, None, None],
+            Command::Play => [None, Some(Played), None, None, Some(Played)],
+            Command::Pause => [None, None, Some(Paused), None, None],
+            Command::Resume => [None, None, None, Some(Played), None],
+            Command::Stop => [None, None, Some(Stopped), Some(Stopped), None],
+            Command::Seek => [None, None, Some(Played), Some(Paused), None],
+            Command::SetVolume => [None, Some(Paired), Some(Played), Some(Paused), None],
+            Command::Capabilities | Command::TransferTo => {
+                [None, Some(Paired), Some(Played), Some(Paused), Some(Stopped)]
+            }
+            Command::Heartbeat | Command::Ping | Command::GetState => STATES.map(Some),
+            Command::Unpair => [
+                None,
+                Some(Unpaired),
+                Some(Unpaired),
+                Some(Unpaired),
+                Some(Unpaired),
+            ],
+            Command::Reset => [Some(Unpaired); 5],
+            Command::Ack | Command::Nack | Command::State | Command::ServerInfo => [None; 5],
+        };
+
+        return match state {
+            Unpaired => unpaired,
+            Paired => paired,
+            Played => played,
+            Paused => paused,
+            Stopped => stopped,
+        };
+    }
+
+    #[test]
+    fn transitions_follow_the_table() {
+        let commands = [
+            Command::Pair,
+            Command::Unpair,
+            Command::Reset,
+            Command::TransferTo,
+            Command::Play,
+            Command::Pause,
+            Command::Resume,
+            Command::Seek,
+            Command::SetVolume,
+            Command::Stop,
+            Command::Capabilities,
+            Command::Heartbeat,
+            Command::Ping,
+            Command::Ack,
+            Command::Nack,
+            Command::GetState,
+            Command::State,
+            Command::ServerInfo,
+        ];
+
+        for from in STATES {
+            for command in commands.clone() {
+                let mut state = from;
+                let result = state.transition(command.clone());
+
+                match expected(from, command.clone()) {
+                    Some(next) => {
+                        assert_eq!(result, Ok(()), "{:?} from {:?}", command, from);
+                        assert_eq!(state, next, "{:?} from {:?}", command, from);
+                    }
+                    None => {
+                        let Err(error) = result else {
+                            panic!("{:?} from {:?} wasn't rejected", command, from);
+                        };
+
+                        assert_eq!(error.from, from);
+                        assert_eq!(error.command, command);
+                        assert_eq!(state, from, "rejected {:?} changed the state", command);
+                    }
+                }
+            }
+        }
+    }
+}
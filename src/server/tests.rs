@@ -0,0 +1,3645 @@
+use std::{
+    collections::HashSet,
+    future,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    body::{self, Body},
+    extract::{ConnectInfo, ws},
+    http::{HeaderMap, HeaderValue, Request, StatusCode},
+};
+use axum_server::tls_rustls::RustlsConfig;
+use futures::{SinkExt, StreamExt, future::BoxFuture, sink};
+use serde_json::{Value, json};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{Notify, RwLock, broadcast, mpsc, oneshot},
+    time,
+};
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream, client_async, connect_async,
+    tungstenite::{self, Message, client::IntoClientRequest},
+};
+use tower::ServiceExt;
+
+use crate::server::{
+    self, Config,
+    access::{self, AccessLog},
+    audit::{AuditRecord, AuditSink},
+    auth::AuthProvider,
+    config::{ConfigError, IdFormat, PairPolicy, RouteConfig, parse_commands},
+    error::ServerError,
+    handlers::{Registration, RegistrationError, send_registration},
+    limit::{RateLimiter, TokenBucket},
+    metrics,
+    observer::{Observer, Peer},
+    protocol::{self, Action, CloseReason, Command, Event, ProtocolError, SCHEMA_VERSION},
+    secret::Secret,
+    state::{Channel, DeviceGuard, RegisterError, State, StateEvent},
+    state_machine::ControllerState,
+};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const VIDEO: &str = "https://example.com/video.mp4";
+
+/// Server running on an ephemeral port.
+struct Server {
+    addr: SocketAddr,
+    state: Arc<State>,
+}
+
+/// Player connected to a [`Server`], along with the registration it received.
+struct Player {
+    socket: Socket,
+    device: String,
+    secret: String,
+}
+
+async fn spawn(config: Config) -> Server {
+    return spawn_with_state(Arc::new(State::new()), config).await;
+}
+
+async fn spawn_with_state(state: Arc<State>, config: Config) -> Server {
+    let router = server::router(state.clone(), &config).await;
+
+    let local = SocketAddr::from(([127, 0, 0, 1], 0));
+    let listener = server::bind_socket(local, config.backlog, config.tcp_keepalive)
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(server::run(
+        vec![listener],
+        router,
+        state.clone(),
+        config.drain_timeout,
+        config.handshake_timeout,
+        future::pending(),
+    ));
+
+    return Server { addr, state };
+}
+
+async fn spawn_default() -> Server {
+    return spawn(Config::builder().build().unwrap()).await;
+}
+
+async fn connect(server: &Server, path: &str) -> Socket {
+    let (socket, _) = connect_async(format!("ws://{}{}", server.addr, path))
+        .await
+        .unwrap();
+
+    return socket;
+}
+
+/// Receives the next message that isn't a ping or pong.
+async fn recv(socket: &mut Socket) -> Option<Message> {
+    loop {
+        let msg = time::timeout(TIMEOUT, socket.next())
+            .await
+            .expect("timed out waiting for a message")?
+            .ok()?;
+
+        match msg {
+            Message::Ping(_) | Message::Pong(_) => continue,
+            msg => return Some(msg),
+        }
+    }
+}
+
+/// Receives the next message and parses it as JSON.
+async fn recv_json(socket: &mut Socket) -> Value {
+    match recv(socket).await {
+        Some(Message::Text(text)) => return serde_json::from_str(text.as_str()).unwrap(),
+        msg => panic!("expected a text message, got {:?}", msg),
+    }
+}
+
+async fn send_json(socket: &mut Socket, value: Value) {
+    socket.send(Message::text(value.to_string())).await.unwrap();
+}
+
+async fn connect_player(server: &Server) -> Player {
+    let mut socket = connect(server, "/ws/player").await;
+
+    assert_eq!(recv_json(&mut socket).await["command"], "ServerInfo");
+
+    let registration = recv_json(&mut socket).await;
+
+    return Player {
+        socket,
+        device: registration["device"].as_str().unwrap().to_string(),
+        secret: registration["secret"].as_str().unwrap().to_string(),
+    };
+}
+
+async fn connect_controller(server: &Server, player: &Player) -> Socket {
+    let path = format!(
+        "/ws/controller?device={}&secret={}",
+        player.device, player.secret
+    );
+
+    let mut socket = connect(server, &path).await;
+
+    assert_eq!(recv_json(&mut socket).await["command"], "ServerInfo");
+
+    return socket;
+}
+
+/// Connects a controller to the player and pairs it.
+async fn pair(server: &Server, player: &mut Player) -> Socket {
+    let mut controller = connect_controller(server, player).await;
+
+    send_json(&mut controller, json!({ "command": "Pair" })).await;
+
+    assert_eq!(
+        recv_json(&mut controller).await,
+        json!({ "command": "Pair", "device": player.device }),
+    );
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Pair");
+
+    return controller;
+}
+
+#[tokio::test]
+async fn pair_play_stop_unpair() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+
+    assert!(server.state.channels.read().await.contains_key(&player.device));
+
+    let mut controller = pair(&server, &mut player).await;
+
+    send_json(&mut controller, json!({ "command": "Play", "url": VIDEO })).await;
+
+    let play = recv_json(&mut player.socket).await;
+    assert_eq!(play["command"], "Play");
+    assert_eq!(play["url"], VIDEO);
+
+    send_json(&mut controller, json!({ "command": "Stop" })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Stop");
+
+    send_json(&mut controller, json!({ "command": "Unpair" })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Unpair");
+
+    // The player stays registered for another controller.
+    assert!(matches!(recv(&mut controller).await, Some(Message::Close(_)) | None));
+    assert!(server.state.channels.read().await.contains_key(&player.device));
+}
+
+#[tokio::test]
+async fn heartbeats_round_trip_without_changing_the_pairing() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    send_json(&mut controller, json!({ "command": "Heartbeat", "payload": "beat-1" })).await;
+
+    // The player echoes the heartbeat, which the relay takes back to the controller.
+    let received = recv_json(&mut player.socket).await;
+    assert_eq!(received["command"], "Heartbeat");
+    assert_eq!(received["payload"], "beat-1");
+
+    send_json(&mut player.socket, received).await;
+
+    let echo = recv_json(&mut controller).await;
+    assert_eq!(echo["command"], "Heartbeat");
+    assert_eq!(echo["payload"], "beat-1");
+
+    send_json(&mut controller, json!({ "command": "GetState" })).await;
+    assert_eq!(
+        recv_json(&mut controller).await,
+        json!({ "command": "State", "state": "Paired" }),
+    );
+
+    // Still paired, the controller can play without pairing again.
+    send_json(&mut controller, json!({ "command": "Play", "url": VIDEO })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Play");
+}
+
+#[tokio::test]
+async fn kilobyte_payloads_reach_the_player() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    let url = format!("{}?padding={}", VIDEO, "a".repeat(1024));
+
+    send_json(&mut controller, json!({ "command": "Play", "url": url })).await;
+
+    assert_eq!(recv_json(&mut player.socket).await["url"], url);
+}
+
+#[tokio::test]
+async fn wrong_secret_is_rejected_without_touching_the_player() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+
+    let path = format!("/ws/controller?device={}&secret=wrong", player.device);
+    let mut intruder = connect(&server, &path).await;
+
+    assert_eq!(recv_json(&mut intruder).await["command"], "ServerInfo");
+    expect_close(&mut intruder, 1008).await;
+
+    assert!(server.state.channels.read().await.contains_key(&player.device));
+
+    // The controller knowing the secret still gets the player.
+    let _controller = pair(&server, &mut player).await;
+}
+
+#[tokio::test]
+async fn two_controllers_drive_the_same_player() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+
+    let mut phone = pair(&server, &mut player).await;
+    let mut tablet = pair(&server, &mut player).await;
+
+    send_json(&mut phone, json!({ "command": "Play", "url": VIDEO })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Play");
+
+    send_json(&mut tablet, json!({ "command": "Play", "url": VIDEO })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Play");
+
+    send_json(&mut tablet, json!({ "command": "Stop" })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Stop");
+
+    send_json(&mut phone, json!({ "command": "Stop" })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Stop");
+}
+
+#[tokio::test]
+async fn player_is_handed_off_between_controllers() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+
+    let mut first = pair(&server, &mut player).await;
+
+    send_json(&mut first, json!({ "command": "Play", "url": VIDEO })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Play");
+
+    send_json(&mut first, json!({ "command": "Unpair" })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Unpair");
+
+    // The second controller starts from a clean state, so it has to play again before pausing.
+    let mut second = pair(&server, &mut player).await;
+
+    send_json(&mut second, json!({ "command": "Pause" })).await;
+    send_json(&mut second, json!({ "command": "Play", "url": VIDEO })).await;
+
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Play");
+}
+
+#[tokio::test]
+async fn player_is_released_when_its_controller_disconnects() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+
+    let mut first = pair(&server, &mut player).await;
+
+    first.close(None).await.unwrap();
+
+    // The player is told it's unpaired rather than left waiting for commands.
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Unpair");
+    assert!(server.state.channels.read().await.contains_key(&player.device));
+
+    let mut second = pair(&server, &mut player).await;
+
+    send_json(&mut second, json!({ "command": "Play", "url": VIDEO })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Play");
+}
+
+/// Connects a player and disconnects it, returning its registration.
+async fn connect_and_leave(server: &Server) -> Value {
+    let mut socket = connect(server, "/ws/player").await;
+
+    assert_eq!(recv_json(&mut socket).await["command"], "ServerInfo");
+
+    let registration = recv_json(&mut socket).await;
+
+    socket.close(None).await.unwrap();
+    while recv(&mut socket).await.is_some() {}
+
+    return registration;
+}
+
+#[tokio::test]
+async fn player_reconnects_within_the_grace_period() {
+    let config = Config::builder()
+        .reconnect_grace(Duration::from_secs(30))
+        .build()
+        .unwrap();
+
+    let server = spawn(config).await;
+
+    let registration = connect_and_leave(&server).await;
+    let device = registration["device"].as_str().unwrap();
+
+    time::timeout(TIMEOUT, async {
+        loop {
+            let parked = match server.state.channels.read().await.get(device) {
+                Some(channel) => channel.read().await.parked.is_some(),
+                None => false,
+            };
+
+            if parked {
+                break;
+            }
+
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("device wasn't kept for the player to reconnect");
+
+    let path = format!("/ws/player?reconnect={}", registration["token"].as_str().unwrap());
+    let mut socket = connect(&server, &path).await;
+
+    assert_eq!(recv_json(&mut socket).await["command"], "ServerInfo");
+    assert_eq!(recv_json(&mut socket).await["device"], device);
+
+    let mut player = Player {
+        socket,
+        device: device.to_string(),
+        secret: registration["secret"].as_str().unwrap().to_string(),
+    };
+
+    let _controller = pair(&server, &mut player).await;
+}
+
+#[tokio::test]
+async fn player_reconnecting_after_the_grace_period_gets_a_new_device() {
+    let grace = Duration::from_millis(100);
+    let server = spawn(Config::builder().reconnect_grace(grace).build().unwrap()).await;
+
+    let registration = connect_and_leave(&server).await;
+    let device = registration["device"].as_str().unwrap();
+
+    time::timeout(TIMEOUT, async {
+        while server.state.channels.read().await.contains_key(device) {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("device wasn't removed after the grace period");
+
+    let path = format!("/ws/player?reconnect={}", registration["token"].as_str().unwrap());
+    let mut socket = connect(&server, &path).await;
+
+    assert_eq!(recv_json(&mut socket).await["command"], "ServerInfo");
+    assert_ne!(recv_json(&mut socket).await["device"], device);
+}
+
+#[tokio::test]
+async fn rejects_players_over_max_devices() {
+    let server = spawn(Config::builder().max_devices(2).build().unwrap()).await;
+
+    let mut first = connect_player(&server).await;
+    let second = connect_player(&server).await;
+
+    let mut rejected = connect(&server, "/ws/player").await;
+
+    match recv(&mut rejected).await {
+        Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), 1013),
+        msg => panic!("expected a close frame, got {:?}", msg),
+    }
+
+    let channels = server.state.channels.read().await;
+    assert_eq!(channels.len(), 2);
+    assert!(channels.contains_key(&first.device));
+    assert!(channels.contains_key(&second.device));
+    drop(channels);
+
+    // The registered players keep working.
+    let mut controller = pair(&server, &mut first).await;
+    send_json(&mut controller, json!({ "command": "Unpair" })).await;
+}
+
+#[tokio::test]
+async fn registers_player_under_requested_code() {
+    let server = spawn_default().await;
+
+    let mut socket = connect(&server, "/ws/player?code=K7P2QX").await;
+
+    assert_eq!(recv_json(&mut socket).await["command"], "ServerInfo");
+
+    let registration = recv_json(&mut socket).await;
+    assert_eq!(registration["device"], "K7P2QX");
+
+    let mut player = Player {
+        socket,
+        device: "K7P2QX".to_string(),
+        secret: registration["secret"].as_str().unwrap().to_string(),
+    };
+
+    let _controller = pair(&server, &mut player).await;
+}
+
+#[tokio::test]
+async fn closing_session_leaves_a_reused_code_alone() {
+    // Without a grace period the old session unregisters its device, with one it parks it.
+    for grace in [Duration::ZERO, Duration::from_secs(30)] {
+        let server = spawn(Config::builder().reconnect_grace(grace).build().unwrap()).await;
+
+        let mut old = connect(&server, "/ws/player?code=K7P2QX").await;
+        recv_json(&mut old).await;
+        recv_json(&mut old).await;
+
+        // Removed while its player is still connected, the code is free to register again.
+        assert!(server.state.unregister_device("K7P2QX").await);
+
+        let mut new = connect(&server, "/ws/player?code=K7P2QX").await;
+        recv_json(&mut new).await;
+
+        let registration = recv_json(&mut new).await;
+        assert_eq!(registration["device"], "K7P2QX");
+
+        old.close(None).await.unwrap();
+        while recv(&mut old).await.is_some() {}
+
+        time::sleep(Duration::from_millis(100)).await;
+
+        let channels = server.state.channels.read().await;
+        let channel = channels.get("K7P2QX").expect("new device was removed").read().await;
+
+        assert_eq!(channel.token, registration["token"].as_str().unwrap());
+        assert!(channel.parked.is_none());
+    }
+}
+
+#[tokio::test]
+async fn rejects_player_requesting_an_invalid_code() {
+    let server = spawn_default().await;
+
+    // Too short, lowercase, with a character left out of short ids and too long.
+    for code in ["K7P2", "k7p2qx", "K7P2Q1", "K7P2QXA", "K7P2Q%2F"] {
+        let url = format!("ws://{}/ws/player?code={}", server.addr, code);
+        let err = connect_async(url).await.unwrap_err();
+
+        let tungstenite::Error::Http(response) = err else {
+            panic!("expected an HTTP error, got {:?}", err);
+        };
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", code);
+    }
+
+    assert!(server.state.channels.read().await.is_empty());
+
+    let refused = server.state.register_device_as(1, 10, "../x".to_string()).await;
+    assert!(matches!(refused, Err(RegisterError::InvalidCode)));
+}
+
+#[tokio::test]
+async fn rejects_player_requesting_a_taken_code() {
+    let server = spawn_default().await;
+
+    let _first = connect(&server, "/ws/player?code=K7P2QX").await;
+
+    // Waits for the first player to be registered.
+    time::timeout(TIMEOUT, async {
+        while !server.state.channels.read().await.contains_key("K7P2QX") {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let mut rejected = connect(&server, "/ws/player?code=K7P2QX").await;
+
+    match recv(&mut rejected).await {
+        Some(Message::Close(Some(frame))) => {
+            assert_eq!(u16::from(frame.code), 4009);
+            assert_eq!(frame.reason, "pairing code taken");
+        }
+        msg => panic!("expected a close frame, got {:?}", msg),
+    }
+
+    assert_eq!(server.state.channels.read().await.len(), 1);
+}
+
+#[tokio::test]
+async fn propagates_configured_request_id_header() {
+    let config = Config::builder()
+        .request_id_header("x-correlation-id")
+        .build()
+        .unwrap();
+
+    let router = server::router(Arc::new(State::new()), &config).await;
+
+    let response = router
+        .clone()
+        .oneshot(
+            Request::get("/metrics")
+                .header("x-correlation-id", "abc123")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.headers()["x-correlation-id"], "abc123");
+
+    let response = router
+        .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    assert!(!response.headers()["x-correlation-id"].is_empty());
+    assert!(!response.headers().contains_key("x-request-id"));
+}
+
+/// Sends a GET request to the router and returns the status and the JSON body.
+async fn get_json(router: axum::Router, request: Request<Body>) -> (StatusCode, Value) {
+    let response = router.oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+    return (status, serde_json::from_slice(&bytes).unwrap());
+}
+
+#[tokio::test]
+async fn connections_over_the_rate_are_too_many_requests() {
+    let server = spawn(Config::builder().connections_per_minute(2).build().unwrap()).await;
+
+    let _first = connect(&server, "/ws/player").await;
+    let _second = connect(&server, "/ws/player").await;
+
+    let err = connect_async(format!("ws://{}/ws/controller?device=a&secret=b", server.addr))
+        .await
+        .unwrap_err();
+
+    let tungstenite::Error::Http(response) = err else {
+        panic!("expected an HTTP error, got {:?}", err);
+    };
+
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+/// Statuses of requests to the player route from `peer`, claiming to be each of the `clients`.
+async fn statuses_through(config: &Config, peer: [u8; 4], clients: &[&str]) -> Vec<StatusCode> {
+    let router = server::router(Arc::new(State::new()), config).await;
+    let mut statuses = Vec::new();
+
+    for client in clients {
+        let request = Request::get("/ws/player")
+            .header("fly-client-ip", *client)
+            .extension(ConnectInfo(SocketAddr::from((peer, 4000))))
+            .body(Body::empty())
+            .unwrap();
+
+        statuses.push(router.clone().oneshot(request).await.unwrap().status());
+    }
+
+    return statuses;
+}
+
+#[tokio::test]
+async fn rate_limit_keys_on_the_client_behind_a_trusted_proxy() {
+    let config = Config::builder()
+        .connections_per_minute(1)
+        .trusted_proxies(vec![IpAddr::from([10, 0, 0, 1])])
+        .build()
+        .unwrap();
+
+    let statuses = statuses_through(&config, [10, 0, 0, 1], &["203.0.113.1", "203.0.113.2"]).await;
+    assert!(!statuses.contains(&StatusCode::TOO_MANY_REQUESTS), "{:?}", statuses);
+
+    let statuses = statuses_through(&config, [10, 0, 0, 1], &["203.0.113.1", "203.0.113.1"]).await;
+    assert_eq!(statuses[1], StatusCode::TOO_MANY_REQUESTS);
+
+    // Other peers can't pose as different clients.
+    let statuses = statuses_through(&config, [10, 0, 0, 2], &["203.0.113.1", "203.0.113.2"]).await;
+    assert_eq!(statuses[1], StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[test]
+fn forwarded_for_is_read_from_the_last_untrusted_address() {
+    let proxies = vec![IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2])];
+    let limiter = RateLimiter::new(1, proxies);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "x-forwarded-for",
+        HeaderValue::from_static("198.51.100.7, 203.0.113.9, 10.0.0.2"),
+    );
+
+    let client = limiter.client_ip(IpAddr::from([10, 0, 0, 1]), &headers);
+    assert_eq!(client, IpAddr::from([203, 0, 113, 9]));
+
+    let peer = limiter.client_ip(IpAddr::from([192, 0, 2, 1]), &headers);
+    assert_eq!(peer, IpAddr::from([192, 0, 2, 1]));
+
+    // Clients within their window are kept by the sweep.
+    assert!(limiter.check(client));
+    assert_eq!(limiter.sweep(), 0);
+    assert!(!limiter.check(client));
+}
+
+#[tokio::test]
+async fn controller_without_device_is_a_bad_request() {
+    let server = spawn_default().await;
+
+    let err = connect_async(format!("ws://{}/ws/controller?secret=abc", server.addr))
+        .await
+        .unwrap_err();
+
+    let tungstenite::Error::Http(response) = err else {
+        panic!("expected an HTTP error, got {:?}", err);
+    };
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let body: Value = serde_json::from_slice(response.body().as_deref().unwrap()).unwrap();
+
+    assert_eq!(
+        body,
+        json!({ "error": { "code": "missing_device", "message": "missing device" } }),
+    );
+}
+
+#[tokio::test]
+async fn controller_without_secret_is_a_bad_request() {
+    let server = spawn_default().await;
+    let player = connect_player(&server).await;
+
+    let url = format!("ws://{}/ws/controller?device={}", server.addr, player.device);
+    let err = connect_async(url).await.unwrap_err();
+
+    let tungstenite::Error::Http(response) = err else {
+        panic!("expected an HTTP error, got {:?}", err);
+    };
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let body: Value = serde_json::from_slice(response.body().as_deref().unwrap()).unwrap();
+
+    assert_eq!(
+        body,
+        json!({ "error": { "code": "missing_secret", "message": "missing secret" } }),
+    );
+
+    // With both parameters the request is upgraded.
+    let _controller = connect_controller(&server, &player).await;
+}
+
+#[tokio::test]
+async fn devices_without_token_is_unauthorized() {
+    let config = Config::builder()
+        .admin_token(Some("token".to_string()))
+        .build()
+        .unwrap();
+
+    let router = server::router(Arc::new(State::new()), &config).await;
+
+    let (status, body) = get_json(
+        router,
+        Request::get("/devices").body(Body::empty()).unwrap(),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"]["code"], "unauthorized");
+    assert!(body["error"]["message"].is_string());
+}
+
+#[tokio::test]
+async fn devices_with_token_lists_the_devices() {
+    let config = Config::builder()
+        .admin_token(Some("token".to_string()))
+        .build()
+        .unwrap();
+
+    let state = Arc::new(State::new());
+    let router = server::router(state.clone(), &config).await;
+
+    let devices = |token: &str| {
+        Request::get("/devices")
+            .header("authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let (status, body) = get_json(router.clone(), devices("token")).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!([]));
+
+    let registered = state.register_device(1, 10, IdFormat::Uuid).await.unwrap();
+
+    let (status, body) = get_json(router.clone(), devices("token")).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!([{ "device": registered.device, "controlled": false }]));
+
+    // A token sharing a prefix with the right one is as wrong as any other.
+    for token in ["toke", "tokens", ""] {
+        let (status, _) = get_json(router.clone(), devices(token)).await;
+
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[tokio::test]
+async fn health_reports_the_device_count() {
+    let state = Arc::new(State::new());
+    let router = server::router(state.clone(), &Config::builder().build().unwrap()).await;
+
+    for _ in 0..2 {
+        state.register_device(1, 10, IdFormat::Uuid).await.unwrap();
+    }
+
+    let request = Request::get("/health").body(Body::empty()).unwrap();
+    let (status, body) = get_json(router, request).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!({ "status": "ok", "devices": 2 }));
+}
+
+#[tokio::test]
+async fn info_reports_build_and_uptime() {
+    let config = Config::builder()
+        .admin_token(Some("token".to_string()))
+        .build()
+        .unwrap();
+
+    let router = server::router(Arc::new(State::new()), &config).await;
+    let request = || Request::get("/info").body(Body::empty()).unwrap();
+
+    let (status, first) = get_json(router.clone(), request()).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(first["version"], env!("CARGO_PKG_VERSION"));
+    assert!(first["commit"].is_string());
+    assert_eq!(first["devices"], 0);
+    assert_eq!(first["controllers"], 0);
+
+    time::sleep(Duration::from_millis(10)).await;
+
+    let (_, second) = get_json(router, request()).await;
+
+    assert!(second["uptime"].as_f64().unwrap() > first["uptime"].as_f64().unwrap());
+}
+
+/// Connects a controller to the player of a server enforcing sequence numbers and pairs it with
+/// the event numbered 0.
+async fn pair_in_sequence(server: &Server, player: &mut Player) -> Socket {
+    let mut controller = connect_controller(server, player).await;
+
+    send_json(&mut controller, json!({ "command": "Pair", "seq": 0 })).await;
+
+    assert_eq!(recv_json(&mut controller).await["command"], "Pair");
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Pair");
+
+    return controller;
+}
+
+#[tokio::test]
+async fn events_in_sequence_are_accepted() {
+    let server = spawn(Config::builder().enforce_seq(true).build().unwrap()).await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair_in_sequence(&server, &mut player).await;
+
+    // Numbers may skip, as long as they increase.
+    for (seq, level) in [(1, 10), (2, 20), (5, 50)] {
+        let event = json!({ "command": "SetVolume", "level": level, "seq": seq });
+        send_json(&mut controller, event).await;
+
+        assert_eq!(recv_json(&mut player.socket).await["level"], level);
+    }
+}
+
+#[tokio::test]
+async fn replayed_events_close_the_controller() {
+    for replayed in [1, 0] {
+        let server = spawn(Config::builder().enforce_seq(true).build().unwrap()).await;
+        let mut player = connect_player(&server).await;
+        let mut controller = pair_in_sequence(&server, &mut player).await;
+
+        send_json(&mut controller, json!({ "command": "Play", "url": VIDEO, "seq": 1 })).await;
+        assert_eq!(recv_json(&mut player.socket).await["url"], VIDEO);
+
+        send_json(&mut controller, json!({ "command": "Stop", "seq": replayed })).await;
+
+        match recv(&mut controller).await {
+            Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), 1002),
+            msg => panic!("expected a close frame, got {:?}", msg),
+        }
+    }
+}
+
+#[tokio::test]
+async fn events_without_sequence_close_the_controller_when_enforced() {
+    let server = spawn(Config::builder().enforce_seq(true).build().unwrap()).await;
+    let player = connect_player(&server).await;
+    let mut controller = connect_controller(&server, &player).await;
+
+    send_json(&mut controller, json!({ "command": "Pair" })).await;
+
+    match recv(&mut controller).await {
+        Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), 1002),
+        msg => panic!("expected a close frame, got {:?}", msg),
+    }
+}
+
+#[tokio::test]
+async fn runs_session_with_custom_queue_depths() {
+    let config = Config::builder()
+        .channel_capacity(1)
+        .controller_queue(Some(16))
+        .build()
+        .unwrap();
+
+    let server = spawn(config).await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    for level in 1..=10 {
+        send_json(&mut controller, json!({ "command": "SetVolume", "level": level })).await;
+    }
+
+    for level in 1..=10 {
+        assert_eq!(recv_json(&mut player.socket).await["level"], level);
+    }
+
+    assert_eq!(
+        Config::builder().controller_queue(Some(0)).build().unwrap_err(),
+        ConfigError::Zero("controller queue"),
+    );
+}
+
+/// Receives two ping events, checking they're about `interval` apart.
+async fn expect_pings(socket: &mut Socket, interval: Duration) {
+    assert_eq!(recv_json(socket).await, json!({ "command": "Ping" }));
+
+    let start = time::Instant::now();
+
+    assert_eq!(recv_json(socket).await, json!({ "command": "Ping" }));
+    assert!(start.elapsed() >= interval / 2);
+}
+
+#[tokio::test]
+async fn ping_events_arrive_on_schedule() {
+    let interval = Duration::from_millis(100);
+    let server = spawn(Config::builder().app_ping_interval(interval).build().unwrap()).await;
+
+    let mut player = connect_player(&server).await;
+    expect_pings(&mut player.socket, interval).await;
+
+    let mut controller = connect_controller(&server, &player).await;
+    expect_pings(&mut controller, interval).await;
+
+    // Echoes are accepted without closing the controller or reaching the player.
+    send_json(&mut controller, json!({ "command": "Ping" })).await;
+    send_json(&mut controller, json!({ "command": "GetState" })).await;
+
+    loop {
+        let reply = recv_json(&mut controller).await;
+
+        if reply["command"] != "Ping" {
+            assert_eq!(reply, json!({ "command": "State", "state": "Unpaired" }));
+
+            break;
+        }
+    }
+}
+
+/// Receives the `Unpair` event and close frame ending an expired session.
+async fn expect_expiry(socket: &mut Socket) {
+    assert_eq!(recv_json(socket).await, json!({ "command": "Unpair" }));
+
+    match recv(socket).await {
+        Some(Message::Close(Some(frame))) => {
+            assert_eq!(u16::from(frame.code), 4001);
+            assert_eq!(frame.reason, "session expired");
+        }
+        msg => panic!("expected a close frame, got {:?}", msg),
+    }
+}
+
+#[tokio::test]
+async fn sessions_expire_after_the_maximum_duration() {
+    let config = Config::builder()
+        .max_session_duration(Duration::from_millis(200))
+        .build()
+        .unwrap();
+
+    let server = spawn(config).await;
+    let mut player = connect_player(&server).await;
+    let mut controller = connect_controller(&server, &player).await;
+
+    send_json(&mut controller, json!({ "command": "Pair" })).await;
+    assert_eq!(recv_json(&mut controller).await["command"], "Pair");
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Pair");
+
+    expect_expiry(&mut player.socket).await;
+    expect_expiry(&mut controller).await;
+
+    // The device is gone with the player, which has to register again.
+    assert!(!server.state.channels.read().await.contains_key(&player.device));
+}
+
+/// Spawns a server with the pair policy and pairs a controller with a player, which then sends a
+/// second `Pair`.
+async fn pair_twice(policy: PairPolicy) -> (Server, Player, Socket) {
+    let server = spawn(Config::builder().pair_policy(policy).build().unwrap()).await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    send_json(&mut controller, json!({ "command": "Pair" })).await;
+
+    return (server, player, controller);
+}
+
+#[tokio::test]
+async fn duplicate_pair_is_ignored_by_default() {
+    let (_server, mut player, mut controller) = pair_twice(PairPolicy::default()).await;
+
+    // The session carries on as if the duplicate was never sent.
+    send_json(&mut controller, json!({ "command": "Play", "url": VIDEO })).await;
+
+    assert_eq!(recv_json(&mut player.socket).await["url"], VIDEO);
+
+    send_json(&mut controller, json!({ "command": "GetState" })).await;
+
+    assert_eq!(
+        recv_json(&mut controller).await,
+        json!({ "command": "State", "state": "Played" }),
+    );
+}
+
+#[tokio::test]
+async fn duplicate_pair_closes_the_controller_with_error_policy() {
+    let (_server, mut player, mut controller) = pair_twice(PairPolicy::Error).await;
+
+    match recv(&mut controller).await {
+        Some(Message::Close(Some(frame))) => {
+            assert_eq!(u16::from(frame.code), 4009);
+            assert_eq!(frame.reason, "already paired");
+        }
+        msg => panic!("expected a close frame, got {:?}", msg),
+    }
+
+    assert_eq!(recv_json(&mut player.socket).await, json!({ "command": "Unpair" }));
+}
+
+#[tokio::test]
+async fn duplicate_pair_pairs_again_with_replace_policy() {
+    let (server, mut player, mut controller) = pair_twice(PairPolicy::Replace).await;
+
+    // The player is released before it's paired again.
+    assert_eq!(recv_json(&mut player.socket).await, json!({ "command": "Unpair" }));
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Pair");
+
+    assert_eq!(
+        recv_json(&mut controller).await,
+        json!({ "command": "Pair", "device": player.device }),
+    );
+
+    send_json(&mut controller, json!({ "command": "Play", "url": VIDEO })).await;
+
+    assert_eq!(recv_json(&mut player.socket).await["url"], VIDEO);
+    assert!(server.state.channels.read().await.contains_key(&player.device));
+}
+
+/// Asserts the next message is a close frame with the code.
+async fn expect_close(socket: &mut Socket, code: u16) {
+    match recv(socket).await {
+        Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), code),
+        msg => panic!("expected a close frame, got {:?}", msg),
+    }
+}
+
+#[tokio::test]
+async fn control_is_transferred_to_another_controller() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut old = pair(&server, &mut player).await;
+
+    send_json(&mut old, json!({ "command": "Play", "url": VIDEO })).await;
+    assert_eq!(recv_json(&mut player.socket).await["url"], VIDEO);
+
+    send_json(&mut old, json!({ "command": "TransferTo", "token": "handoff" })).await;
+
+    time::timeout(TIMEOUT, async {
+        loop {
+            let channels = server.state.channels.read().await;
+
+            if channels[&player.device].read().await.handoff.is_some() {
+                break;
+            }
+
+            drop(channels);
+
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let path = |token: &str| format!("/ws/controller?device={}&handoff={}", player.device, token);
+
+    // A wrong token is turned away without cancelling the handoff.
+    let mut intruder = connect(&server, &path("guess")).await;
+    assert_eq!(recv_json(&mut intruder).await["command"], "ServerInfo");
+    expect_close(&mut intruder, 1008).await;
+
+    let mut new = connect(&server, &path("handoff")).await;
+    assert_eq!(recv_json(&mut new).await["command"], "ServerInfo");
+    assert_eq!(
+        recv_json(&mut new).await,
+        json!({ "command": "Pair", "device": player.device }),
+    );
+
+    // The old controller is told it's detached, and the player isn't unpaired.
+    assert_eq!(recv_json(&mut old).await, json!({ "command": "Unpair" }));
+    expect_close(&mut old, 1000).await;
+
+    // The new controller carries on from the playing state without pairing.
+    send_json(&mut new, json!({ "command": "Pause" })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Pause");
+
+    // The token can't be used twice.
+    let mut late = connect(&server, &path("handoff")).await;
+    assert_eq!(recv_json(&mut late).await["command"], "ServerInfo");
+    expect_close(&mut late, 1008).await;
+}
+
+#[tokio::test]
+async fn silent_controller_is_disconnected_and_the_player_unpaired() {
+    let config = Config::builder()
+        .idle_timeout(Duration::from_millis(200))
+        .build()
+        .unwrap();
+
+    let server = spawn(config).await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    // The controller says nothing after pairing.
+    expect_close(&mut controller, 4008).await;
+
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Unpair");
+}
+
+#[tokio::test]
+async fn silent_unpaired_controller_leaves_the_player_alone() {
+    let config = Config::builder()
+        .idle_timeout(Duration::from_millis(200))
+        .build()
+        .unwrap();
+
+    let server = spawn(config).await;
+    let mut player = connect_player(&server).await;
+    let mut controller = connect_controller(&server, &player).await;
+
+    expect_close(&mut controller, 4008).await;
+
+    // The controller never paired, so there's nothing to release on the player.
+    let released = time::timeout(Duration::from_millis(200), recv(&mut player.socket)).await;
+
+    assert!(released.is_err(), "player got {:?}", released);
+}
+
+#[tokio::test]
+async fn reset_allows_pairing_again_on_the_same_connection() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    send_json(&mut controller, json!({ "command": "Reset" })).await;
+
+    assert_eq!(recv_json(&mut player.socket).await, json!({ "command": "Unpair" }));
+
+    send_json(&mut controller, json!({ "command": "Pair" })).await;
+
+    assert_eq!(
+        recv_json(&mut controller).await,
+        json!({ "command": "Pair", "device": player.device }),
+    );
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Pair");
+
+    send_json(&mut controller, json!({ "command": "Play", "url": VIDEO })).await;
+
+    assert_eq!(recv_json(&mut player.socket).await["url"], VIDEO);
+}
+
+#[test]
+fn unknown_allowed_commands_are_rejected() {
+    let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect::<Vec<_>>();
+
+    assert_eq!(
+        parse_commands(names(&["Play", "Stop"])).unwrap(),
+        HashSet::from([Command::Play, Command::Stop]),
+    );
+
+    assert_eq!(parse_commands(Vec::new()).unwrap().len(), protocol::CONTROLLER_COMMANDS.len());
+
+    // Any unknown name fails the configuration, not only when all of them are.
+    for invalid in [&["stop"][..], &["Play", "stop"][..], &["Ack"][..]] {
+        let error = parse_commands(names(invalid)).unwrap_err();
+
+        assert!(matches!(error, ConfigError::UnknownCommand(_)), "{:?}", error);
+    }
+}
+
+#[tokio::test]
+async fn disallowed_commands_close_the_controller() {
+    let allowed = [Command::Pair, Command::Play, Command::Pause];
+
+    let config = Config::builder()
+        .allowed_commands(allowed.into_iter().collect())
+        .build()
+        .unwrap();
+
+    let server = spawn(config).await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    send_json(&mut controller, json!({ "command": "Play", "url": VIDEO })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Play");
+
+    send_json(&mut controller, json!({ "command": "Stop" })).await;
+
+    match recv(&mut controller).await {
+        Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), 1002),
+        msg => panic!("expected a close frame, got {:?}", msg),
+    }
+}
+
+#[tokio::test]
+async fn shutdown_stops_sessions() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+
+    server.state.shutdown();
+
+    assert!(matches!(recv(&mut player.socket).await, Some(Message::Close(_)) | None));
+
+    time::timeout(TIMEOUT, async {
+        while server.state.channels.read().await.contains_key(&player.device) {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("device wasn't removed");
+}
+
+#[tokio::test]
+async fn shutdown_signal_drains_players_with_a_close_frame() {
+    let config = Config::builder().build().unwrap();
+    let state = Arc::new(State::new());
+    let router = server::router(state.clone(), &config).await;
+
+    let listener = server::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (signal, signalled) = oneshot::channel::<()>();
+
+    tokio::spawn(server::run(
+        vec![listener],
+        router,
+        state.clone(),
+        config.drain_timeout,
+        config.handshake_timeout,
+        async {
+            let _ = signalled.await;
+        },
+    ));
+
+    let server = Server { addr, state };
+    let mut player = connect_player(&server).await;
+
+    signal.send(()).unwrap();
+
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Unpair");
+    assert!(matches!(recv(&mut player.socket).await, Some(Message::Close(_))));
+}
+
+#[tokio::test]
+async fn shutdown_removes_parked_devices_like_any_other() {
+    let config = Config::builder()
+        .reconnect_grace(Duration::from_secs(30))
+        .build()
+        .unwrap();
+
+    let state = Arc::new(State::new());
+    let router = server::router(state.clone(), &config).await;
+
+    let listener = server::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let (signal, signalled) = oneshot::channel::<()>();
+
+    tokio::spawn(server::run(
+        vec![listener],
+        router,
+        state.clone(),
+        config.drain_timeout,
+        config.handshake_timeout,
+        async {
+            let _ = signalled.await;
+        },
+    ));
+
+    let server = Server { addr, state };
+    let registration = connect_and_leave(&server).await;
+    let device = registration["device"].as_str().unwrap().to_string();
+    let secret = Secret::from(registration["secret"].as_str().unwrap());
+
+    time::timeout(TIMEOUT, async {
+        loop {
+            if let Some(channel) = server.state.channels.read().await.get(&device)
+                && channel.read().await.parked.is_some()
+            {
+                break;
+            }
+
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("device wasn't parked");
+
+    let mut events = server.state.events.subscribe();
+
+    signal.send(()).unwrap();
+
+    let removed = time::timeout(TIMEOUT, events.recv()).await.unwrap().unwrap();
+
+    assert_eq!(removed, StateEvent::DeviceRemoved { device: device.clone() });
+    assert!(!server.state.auth.verify(&device, &secret).await);
+}
+
+#[tokio::test]
+async fn player_nack_reaches_controller() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    send_json(
+        &mut controller,
+        json!({ "command": "Play", "url": "https://example.com/video.avi", "id": "42" }),
+    )
+    .await;
+
+    let play = recv_json(&mut player.socket).await;
+    assert_eq!(play["id"], "42");
+
+    let nack = json!({ "command": "Nack", "reason": "unsupported format", "id": "42" });
+    send_json(&mut player.socket, nack.clone()).await;
+
+    assert_eq!(recv_json(&mut controller).await, nack);
+}
+
+#[tokio::test]
+async fn unacknowledged_command_times_out() {
+    let config = Config::builder()
+        .ack_timeout(Duration::from_millis(100))
+        .build()
+        .unwrap();
+
+    let server = spawn(config).await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    send_json(
+        &mut controller,
+        json!({ "command": "Play", "url": "https://example.com/video.avi", "id": "42" }),
+    )
+    .await;
+
+    assert_eq!(recv_json(&mut player.socket).await["id"], "42");
+
+    // The player never acknowledges the command.
+    assert_eq!(
+        recv_json(&mut controller).await,
+        json!({ "command": "Nack", "reason": "timeout", "id": "42" }),
+    );
+}
+
+#[tokio::test]
+async fn unknown_device_closes_with_device_not_found() {
+    let server = spawn_default().await;
+
+    let mut controller = connect(&server, "/ws/controller?device=nowhere&secret=secret").await;
+
+    assert_eq!(recv_json(&mut controller).await["command"], "ServerInfo");
+    expect_close(&mut controller, 4004).await;
+}
+
+#[test]
+fn close_reasons_map_to_documented_codes() {
+    let reasons = [
+        (CloseReason::Transferred, 1000, "control transferred"),
+        (CloseReason::ProtocolError, 1002, "protocol error"),
+        (CloseReason::InvalidSecret, 1008, "invalid secret"),
+        (CloseReason::InternalError, 1011, "internal error"),
+        (CloseReason::Backpressure, 1013, "too many commands"),
+        (CloseReason::TooManyDevices, 1013, "too many devices"),
+        (CloseReason::SessionExpired, 4001, "session expired"),
+        (CloseReason::DeviceNotFound, 4004, "device not found"),
+        (CloseReason::IdleTimeout, 4008, "idle timeout"),
+        (CloseReason::AlreadyPaired, 4009, "already paired"),
+        (CloseReason::CodeTaken, 4009, "pairing code taken"),
+        (CloseReason::UnsupportedSchema, 4015, "unsupported schema version"),
+    ];
+
+    for (reason, code, text) in reasons {
+        let frame = reason.frame();
+
+        assert_eq!(frame.code, code, "{:?}", reason);
+        assert_eq!(frame.reason.as_str(), text, "{:?}", reason);
+    }
+}
+
+#[test]
+fn event_without_id_deserializes() {
+    let event: Event = serde_json::from_str(r#"{"command":"Play","url":"url"}"#).unwrap();
+
+    assert_eq!(event.command(), Command::Play);
+    assert_eq!(event.id, None);
+
+    // Events without an id serialize the same as before ids existed.
+    assert_eq!(
+        serde_json::to_value(&event).unwrap(),
+        json!({ "command": "Play", "url": "url" }),
+    );
+}
+
+#[test]
+fn event_with_id_round_trips() {
+    let text = r#"{"command":"Stop","id":"7"}"#;
+    let event: Event = serde_json::from_str(text).unwrap();
+
+    assert_eq!(event.id.as_deref(), Some("7"));
+    assert_eq!(serde_json::to_string(&event).unwrap(), text);
+}
+
+#[tokio::test]
+async fn ids_are_preserved_through_forwarding() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = connect_controller(&server, &player).await;
+
+    send_json(&mut controller, json!({ "command": "Pair", "id": "1" })).await;
+    assert_eq!(recv_json(&mut controller).await["id"], "1");
+    assert_eq!(recv_json(&mut player.socket).await["id"], "1");
+
+    send_json(
+        &mut controller,
+        json!({ "command": "SetVolume", "level": 150, "id": "2" }),
+    )
+    .await;
+
+    let volume = recv_json(&mut player.socket).await;
+    assert_eq!(volume["level"], 100);
+    assert_eq!(volume["id"], "2");
+}
+
+#[tokio::test]
+async fn unanswered_pings_unregister_the_device() {
+    let config = Config::builder()
+        .ping_interval(Duration::from_millis(50))
+        .ping_timeout(Duration::from_millis(200))
+        .reconnect_grace(Duration::ZERO)
+        .build()
+        .unwrap();
+
+    let server = spawn(config).await;
+
+    // The player stops reading after its registration, so it never answers the pings.
+    let player = connect_player(&server).await;
+
+    time::timeout(TIMEOUT, async {
+        while server.state.channels.read().await.contains_key(&player.device) {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("device of the unresponsive player wasn't removed");
+}
+
+#[test]
+fn zero_ping_interval_and_idle_timeout_are_rejected() {
+    assert_eq!(
+        Config::builder().ping_interval(Duration::ZERO).build().unwrap_err(),
+        ConfigError::Zero("ping interval"),
+    );
+
+    assert_eq!(
+        Config::builder().idle_timeout(Duration::ZERO).build().unwrap_err(),
+        ConfigError::Zero("idle timeout"),
+    );
+}
+
+#[tokio::test]
+async fn reaper_removes_orphaned_channels() {
+    let server = spawn(
+        Config::builder()
+            .reap_interval(Duration::from_millis(50))
+            .build()
+            .unwrap(),
+    )
+    .await;
+
+    let player = connect_player(&server).await;
+
+    // A channel whose player loop died without cleaning up after itself.
+    let (sender, _) = mpsc::channel(1);
+    let (replies, _) = broadcast::channel(1);
+
+    server.state.channels.write().await.insert(
+        "orphan".to_string(),
+        RwLock::new(Channel {
+            sender,
+            secret: Secret::from("secret"),
+            controllers: 0,
+            token: "token".to_string(),
+            parked: None,
+            state: ControllerState::default(),
+            replies,
+            bucket: TokenBucket::default(),
+            connected_at: SystemTime::now(),
+            handoff: None,
+        }),
+    );
+
+    time::timeout(TIMEOUT, async {
+        while server.state.channels.read().await.contains_key("orphan") {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("orphaned channel wasn't reaped");
+
+    assert!(server.state.channels.read().await.contains_key(&player.device));
+}
+
+/// Connects a player asking for the given subprotocols.
+async fn connect_with_protocol(
+    server: &Server,
+    protocol: &str,
+) -> Result<(Socket, tungstenite::handshake::client::Response), tungstenite::Error> {
+    let mut request = format!("ws://{}/ws/player", server.addr)
+        .into_client_request()
+        .unwrap();
+
+    request
+        .headers_mut()
+        .insert("sec-websocket-protocol", protocol.parse().unwrap());
+
+    return connect_async(request).await;
+}
+
+#[tokio::test]
+async fn negotiates_matching_subprotocol() {
+    let server = spawn_default().await;
+
+    let (_, response) = connect_with_protocol(&server, "other, televiu.v1")
+        .await
+        .unwrap();
+
+    assert_eq!(response.headers()["sec-websocket-protocol"], "televiu.v1");
+}
+
+#[tokio::test]
+async fn rejects_unsupported_subprotocol() {
+    let server = spawn_default().await;
+
+    let err = connect_with_protocol(&server, "televiu.v2").await.unwrap_err();
+
+    let tungstenite::Error::Http(response) = err else {
+        panic!("expected an HTTP error, got {:?}", err);
+    };
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+/// Connects a controller speaking JSON-RPC to the player and pairs it with the request id 1.
+async fn pair_over_rpc(server: &Server, player: &mut Player) -> Socket {
+    let mut request = format!(
+        "ws://{}/ws/controller?device={}&secret={}",
+        server.addr, player.device, player.secret
+    )
+    .into_client_request()
+    .unwrap();
+
+    request
+        .headers_mut()
+        .insert("sec-websocket-protocol", "televiu.jsonrpc".parse().unwrap());
+
+    let (mut controller, response) = connect_async(request).await.unwrap();
+
+    assert_eq!(response.headers()["sec-websocket-protocol"], "televiu.jsonrpc");
+    assert_eq!(recv_json(&mut controller).await["method"], "server_info");
+
+    send_json(&mut controller, json!({ "jsonrpc": "2.0", "method": "pair", "id": 1 })).await;
+
+    assert_eq!(
+        recv_json(&mut controller).await,
+        json!({ "jsonrpc": "2.0", "result": { "device": player.device }, "id": 1 }),
+    );
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Pair");
+
+    return controller;
+}
+
+#[tokio::test]
+async fn json_rpc_play_call_gets_a_result() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair_over_rpc(&server, &mut player).await;
+
+    let url = "https://example.com/video.avi";
+
+    send_json(
+        &mut controller,
+        json!({ "jsonrpc": "2.0", "method": "play", "params": { "url": url }, "id": 2 }),
+    )
+    .await;
+
+    // The player speaks the native protocol whatever its controllers do.
+    let play = recv_json(&mut player.socket).await;
+    assert_eq!(play, json!({ "command": "Play", "url": url, "id": "2" }));
+
+    send_json(&mut player.socket, json!({ "command": "Ack", "id": "2" })).await;
+
+    assert_eq!(
+        recv_json(&mut controller).await,
+        json!({ "jsonrpc": "2.0", "result": {}, "id": 2 }),
+    );
+
+    send_json(&mut player.socket, json!({ "command": "Nack", "reason": "busy", "id": "2" })).await;
+
+    assert_eq!(
+        recv_json(&mut controller).await,
+        json!({ "jsonrpc": "2.0", "error": { "code": -32000, "message": "busy" }, "id": 2 }),
+    );
+}
+
+#[tokio::test]
+async fn invalid_json_rpc_requests_get_an_error() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair_over_rpc(&server, &mut player).await;
+
+    send_json(&mut controller, json!({ "jsonrpc": "2.0", "method": "rewind", "id": 3 })).await;
+
+    let response = recv_json(&mut controller).await;
+    assert_eq!(response["error"]["code"], -32601);
+    assert_eq!(response["id"], 3);
+
+    send_json(
+        &mut controller,
+        json!({ "jsonrpc": "2.0", "method": "seek", "params": { "position": "far" }, "id": 4 }),
+    )
+    .await;
+
+    let response = recv_json(&mut controller).await;
+    assert_eq!(response["error"]["code"], -32602);
+    assert_eq!(response["id"], 4);
+
+    // The session goes on after the errors.
+    send_json(&mut controller, json!({ "jsonrpc": "2.0", "method": "pause" })).await;
+
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Pause");
+}
+
+#[tokio::test]
+async fn json_rpc_is_selected_with_the_query() {
+    let server = spawn_default().await;
+    let player = connect_player(&server).await;
+
+    let path = format!(
+        "/ws/controller?device={}&secret={}&protocol=jsonrpc",
+        player.device, player.secret
+    );
+
+    let mut controller = connect(&server, &path).await;
+
+    let info = recv_json(&mut controller).await;
+    assert_eq!(info["jsonrpc"], "2.0");
+    assert_eq!(info["method"], "server_info");
+    assert_eq!(info["params"]["protocol_version"], 1);
+}
+
+#[tokio::test]
+async fn large_payloads_round_trip_for_clients_asking_for_compression() {
+    let server = spawn(Config::builder().max_message_size(64 * 1024).build().unwrap()).await;
+    let mut player = connect_player(&server).await;
+
+    let mut request = format!(
+        "ws://{}/ws/controller?device={}&secret={}",
+        server.addr, player.device, player.secret
+    )
+    .into_client_request()
+    .unwrap();
+
+    request
+        .headers_mut()
+        .insert("sec-websocket-extensions", "permessage-deflate".parse().unwrap());
+
+    let (mut controller, response) = connect_async(request).await.unwrap();
+
+    // The extension isn't negotiated, so the messages are exchanged uncompressed.
+    assert!(!response.headers().contains_key("sec-websocket-extensions"));
+    assert_eq!(recv_json(&mut controller).await["command"], "ServerInfo");
+
+    send_json(&mut controller, json!({ "command": "Pair" })).await;
+    recv_json(&mut controller).await;
+    recv_json(&mut player.socket).await;
+
+    let url = format!("https://example.com/{}", "a".repeat(32 * 1024));
+    send_json(&mut controller, json!({ "command": "Play", "url": url })).await;
+
+    assert_eq!(recv_json(&mut player.socket).await["url"], url);
+}
+
+#[tokio::test]
+async fn accepts_clients_without_subprotocol() {
+    let server = spawn_default().await;
+
+    let (_, response) = connect_async(format!("ws://{}/ws/player", server.addr))
+        .await
+        .unwrap();
+
+    assert!(!response.headers().contains_key("sec-websocket-protocol"));
+}
+
+#[tokio::test]
+async fn register_device_inserts_distinct_devices() {
+    let state = State::new();
+
+    let first = state.register_device(1, 2, IdFormat::Uuid).await.unwrap();
+    let second = state.register_device(1, 2, IdFormat::Uuid).await.unwrap();
+
+    assert_ne!(first.device, second.device);
+
+    let channels = state.channels.read().await;
+    assert!(channels.contains_key(&first.device));
+    assert!(channels.contains_key(&second.device));
+    assert_eq!(channels[&first.device].read().await.secret, first.secret);
+    drop(channels);
+
+    assert!(state.register_device(1, 2, IdFormat::Uuid).await.is_none());
+    assert_eq!(state.channels.read().await.len(), 2);
+}
+
+#[tokio::test]
+async fn device_guard_removes_device_of_aborted_session() {
+    let state = Arc::new(State::new());
+
+    let aborted = state.register_device(1, 10, IdFormat::Uuid).await.unwrap();
+    let guard = DeviceGuard::new(state.clone(), aborted.device.clone(), aborted.token);
+
+    let session = tokio::spawn(async move {
+        let _guard = guard;
+
+        future::pending::<()>().await;
+    });
+
+    session.abort();
+    assert!(session.await.unwrap_err().is_cancelled());
+
+    time::timeout(TIMEOUT, async {
+        while state.channels.read().await.contains_key(&aborted.device) {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("device of the aborted session wasn't removed");
+
+    // A disarmed guard leaves the device to the session.
+    let kept = state.register_device(1, 10, IdFormat::Uuid).await.unwrap();
+    DeviceGuard::new(state.clone(), kept.device.clone(), kept.token).disarm();
+
+    time::sleep(Duration::from_millis(50)).await;
+
+    assert!(state.channels.read().await.contains_key(&kept.device));
+}
+
+#[tokio::test]
+async fn unregister_device_is_idempotent() {
+    let state = State::new();
+    let registered = state.register_device(1, 10, IdFormat::Uuid).await.unwrap();
+
+    assert!(state.unregister_device(&registered.device).await);
+    assert!(!state.channels.read().await.contains_key(&registered.device));
+
+    assert!(!state.unregister_device(&registered.device).await);
+    assert!(!state.unregister_device("unknown").await);
+}
+
+#[tokio::test]
+async fn snapshot_lists_registered_devices() {
+    let state = State::new();
+    let before = SystemTime::now();
+
+    let kept = state.register_device(1, 10, IdFormat::Uuid).await.unwrap();
+    let removed = state.register_device(1, 10, IdFormat::Uuid).await.unwrap();
+
+    state.channels.read().await[&kept.device].write().await.controllers = 1;
+    state.unregister_device(&removed.device).await;
+
+    let snapshot = state.snapshot().await;
+
+    assert_eq!(snapshot.len(), 1);
+    assert_eq!(snapshot[0].device, kept.device);
+    assert!(snapshot[0].attached);
+    assert!(!snapshot[0].parked);
+    assert!(snapshot[0].connected_at >= before);
+
+    // Nothing in the snapshot gives the secret of the device away.
+    assert!(!format!("{:?}", snapshot).contains(kept.secret.expose()));
+}
+
+#[tokio::test]
+async fn state_carries_any_message_type() {
+    let state: State<String> = State::default();
+    let mut registered = state.register_device(1, 10, IdFormat::Short).await.unwrap();
+
+    let sender = state.channels.read().await[&registered.device].read().await.sender.clone();
+    sender.send("message".to_string()).await.unwrap();
+
+    assert_eq!(registered.receiver.recv().await.unwrap(), "message");
+    assert!(state.unregister_device(&registered.device).await);
+
+    let state: State<Event> = State::new();
+    let mut registered = state.register_device(1, 10, IdFormat::Short).await.unwrap();
+
+    let sender = state.channels.read().await[&registered.device].read().await.sender.clone();
+    sender.send(Event::new(Action::Stop)).await.unwrap();
+
+    assert_eq!(registered.receiver.recv().await.unwrap(), Event::new(Action::Stop));
+    assert!(state.unregister_device(&registered.device).await);
+}
+
+#[tokio::test]
+async fn serves_over_tls() {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("televiu-tls-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let cert = dir.join("cert.pem");
+    let key = dir.join("key.pem");
+    std::fs::write(&cert, certified.cert.pem()).unwrap();
+    std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+
+    let config = Config::builder().build().unwrap();
+    let state = Arc::new(State::new());
+    let router = server::router(state.clone(), &config).await;
+
+    let tls = RustlsConfig::from_pem_file(&cert, &key).await.unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(server::run_tls(
+        vec![listener],
+        router,
+        state,
+        config.drain_timeout,
+        config.handshake_timeout,
+        tls,
+        future::pending(),
+    ));
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+
+    let client = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(client));
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let name = rustls::pki_types::ServerName::try_from("localhost").unwrap();
+
+    let stream = time::timeout(TIMEOUT, connector.connect(name, stream))
+        .await
+        .expect("timed out waiting for the TLS handshake");
+
+    assert!(stream.is_ok());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Sink keeping the audit records in memory.
+#[derive(Default)]
+struct MemoryAuditSink {
+    records: Mutex<Vec<AuditRecord>>,
+}
+
+impl AuditSink for MemoryAuditSink {
+    fn record(&self, record: &AuditRecord) {
+        self.records.lock().unwrap().push(record.clone());
+    }
+}
+
+#[tokio::test]
+async fn audit_records_session_commands_in_order() {
+    let sink = Arc::new(MemoryAuditSink::default());
+    let server = spawn(Config::builder().audit(sink.clone()).build().unwrap()).await;
+
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    send_json(&mut controller, json!({ "command": "Play", "url": VIDEO })).await;
+    send_json(&mut controller, json!({ "command": "Stop" })).await;
+    send_json(&mut controller, json!({ "command": "Unpair" })).await;
+
+    // The session is over once the server drops the controller.
+    assert!(matches!(recv(&mut controller).await, Some(Message::Close(_)) | None));
+
+    let records = sink.records.lock().unwrap();
+    let commands: Vec<Command> = records.iter().map(|record| record.command.clone()).collect();
+
+    assert_eq!(
+        commands,
+        vec![Command::Pair, Command::Play, Command::Stop, Command::Unpair],
+    );
+    assert!(records.iter().all(|record| record.device == player.device));
+    assert!(records.iter().all(|record| record.ip.is_loopback()));
+}
+
+#[tokio::test]
+async fn channel_carries_typed_events() {
+    let state = State::new();
+    let mut registered = state.register_device(1, 10, IdFormat::Uuid).await.unwrap();
+
+    let sender = state.channels.read().await[&registered.device]
+        .read()
+        .await
+        .sender
+        .clone();
+
+    sender
+        .send(Event {
+            action: Action::Seek { position: 12.5 },
+            id: Some("1".to_string()),
+            schema_version: SCHEMA_VERSION,
+            seq: None,
+        })
+        .await
+        .unwrap();
+
+    let event = registered.receiver.recv().await.unwrap();
+
+    assert_eq!(event.action, Action::Seek { position: 12.5 });
+    assert_eq!(event.id.as_deref(), Some("1"));
+}
+
+#[tokio::test]
+async fn binary_json_frames_are_read_as_text() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    let play = json!({ "command": "Play", "url": VIDEO }).to_string();
+    controller.send(Message::binary(play.into_bytes())).await.unwrap();
+
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Play");
+}
+
+#[tokio::test]
+async fn binary_frames_that_are_not_utf8_are_a_protocol_error() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    controller.send(Message::binary(vec![0xff, 0xfe, 0xfd])).await.unwrap();
+
+    expect_close(&mut controller, 1002).await;
+}
+
+#[tokio::test]
+async fn malformed_controller_input_is_not_forwarded() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    send_json(&mut controller, json!({ "command": "Dance" })).await;
+    send_json(&mut controller, json!({ "command": "Play", "url": VIDEO })).await;
+
+    let play = recv_json(&mut player.socket).await;
+
+    assert_eq!(play, json!({ "command": "Play", "url": VIDEO }));
+}
+
+#[tokio::test]
+async fn invalid_json_from_controller_is_a_protocol_error() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    controller
+        .send(Message::text(r#"{"command":"Play","#))
+        .await
+        .unwrap();
+
+    match recv(&mut controller).await {
+        Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), 1002),
+        msg => panic!("expected a close frame, got {:?}", msg),
+    }
+}
+
+/// Reads the counter, labels included, from the rendered metrics, which is zero until recorded.
+async fn counter(name: &str) -> u64 {
+    return metrics::render()
+        .await
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.trim().parse().ok())
+        .unwrap_or(0);
+}
+
+#[tokio::test]
+async fn metrics_count_the_commands_of_a_session() {
+    let played = r#"commands_total{command="Play"}"#;
+    let before = counter(played).await;
+
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    send_json(&mut controller, json!({ "command": "Play", "url": VIDEO })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Play");
+
+    let router = server::router(server.state.clone(), &Config::builder().build().unwrap()).await;
+
+    let response = router
+        .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let metrics = String::from_utf8(bytes.to_vec()).unwrap();
+
+    assert!(metrics.contains("active_players"));
+    assert!(metrics.contains("active_controllers"));
+    assert!(counter(played).await > before);
+}
+
+#[tokio::test]
+async fn failed_forwards_are_counted() {
+    let server = spawn_default().await;
+
+    let errors = r#"forward_errors_total{reason="closed"}"#;
+    let before = counter(errors).await;
+
+    // A channel whose player is gone, which the reaper hasn't removed yet.
+    let (sender, _) = mpsc::channel(1);
+    let (replies, _) = broadcast::channel(1);
+    let secret = server.state.auth.issue("gone").await;
+
+    server.state.channels.write().await.insert(
+        "gone".to_string(),
+        RwLock::new(Channel {
+            sender,
+            secret: secret.clone(),
+            controllers: 0,
+            token: "token".to_string(),
+            parked: None,
+            state: ControllerState::default(),
+            replies,
+            bucket: TokenBucket::default(),
+            connected_at: SystemTime::now(),
+            handoff: None,
+        }),
+    );
+
+    let path = format!("/ws/controller?device=gone&secret={}", secret.expose());
+    let mut controller = connect(&server, &path).await;
+
+    assert_eq!(recv_json(&mut controller).await["command"], "ServerInfo");
+
+    send_json(&mut controller, json!({ "command": "Pair" })).await;
+
+    assert!(matches!(recv(&mut controller).await, Some(Message::Close(_)) | None));
+    assert!(counter(errors).await > before);
+}
+
+#[tokio::test]
+async fn controller_exits_when_the_player_receiver_is_dropped() {
+    let server = spawn_default().await;
+
+    let (sender, receiver) = mpsc::channel(8);
+    let (replies, _) = broadcast::channel(1);
+    let secret = server.state.auth.issue("dropped").await;
+
+    server.state.channels.write().await.insert(
+        "dropped".to_string(),
+        RwLock::new(Channel {
+            sender,
+            secret: secret.clone(),
+            controllers: 0,
+            token: "token".to_string(),
+            parked: None,
+            state: ControllerState::default(),
+            replies,
+            bucket: TokenBucket::default(),
+            connected_at: SystemTime::now(),
+            handoff: None,
+        }),
+    );
+
+    let path = format!("/ws/controller?device=dropped&secret={}", secret.expose());
+    let mut controller = connect(&server, &path).await;
+
+    assert_eq!(recv_json(&mut controller).await["command"], "ServerInfo");
+
+    send_json(&mut controller, json!({ "command": "Pair" })).await;
+    assert_eq!(recv_json(&mut controller).await["command"], "Pair");
+
+    // The player goes away in the middle of the session.
+    drop(receiver);
+
+    send_json(&mut controller, json!({ "command": "Play", "url": VIDEO })).await;
+
+    assert!(matches!(recv(&mut controller).await, Some(Message::Close(_)) | None));
+
+    // The session cleans up after itself, which a panicking task wouldn't.
+    time::timeout(TIMEOUT, async {
+        loop {
+            let attached = match server.state.channels.read().await.get("dropped") {
+                Some(channel) => channel.read().await.controllers,
+                None => 0,
+            };
+
+            if attached == 0 {
+                break;
+            }
+
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("controller session didn't end");
+}
+
+#[tokio::test]
+async fn session_durations_are_recorded() {
+    let sessions = "session_duration_seconds_count";
+
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+
+    // Sessions ending with an unpair and with a closed connection are both recorded.
+    for end in [json!({ "command": "Unpair" }), Value::Null] {
+        let before = counter(sessions).await;
+
+        let mut controller = pair(&server, &mut player).await;
+
+        match end {
+            Value::Null => controller.close(None).await.unwrap(),
+            end => send_json(&mut controller, end).await,
+        }
+
+        assert_eq!(recv_json(&mut player.socket).await["command"], "Unpair");
+
+        time::timeout(TIMEOUT, async {
+            while counter(sessions).await <= before {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("session duration wasn't recorded");
+    }
+}
+
+#[tokio::test]
+async fn flooding_a_full_player_channel_closes_the_controller() {
+    let server = spawn_default().await;
+
+    let dropped = r#"commands_dropped_total{reason="backpressure"}"#;
+    let before = counter(dropped).await;
+
+    // Nobody reads the receiver, so the channel is full after two commands.
+    let (sender, mut receiver) = mpsc::channel(2);
+    let (replies, _) = broadcast::channel(1);
+    let secret = server.state.auth.issue("flooded").await;
+
+    server.state.channels.write().await.insert(
+        "flooded".to_string(),
+        RwLock::new(Channel {
+            sender,
+            secret: secret.clone(),
+            controllers: 0,
+            token: "token".to_string(),
+            parked: None,
+            state: ControllerState::default(),
+            replies,
+            bucket: TokenBucket::default(),
+            connected_at: SystemTime::now(),
+            handoff: None,
+        }),
+    );
+
+    let path = format!("/ws/controller?device=flooded&secret={}", secret.expose());
+    let mut controller = connect(&server, &path).await;
+
+    assert_eq!(recv_json(&mut controller).await["command"], "ServerInfo");
+
+    send_json(&mut controller, json!({ "command": "Pair" })).await;
+    assert_eq!(recv_json(&mut controller).await["command"], "Pair");
+
+    send_json(&mut controller, json!({ "command": "Play", "url": VIDEO })).await;
+    send_json(&mut controller, json!({ "command": "Pause" })).await;
+
+    expect_close(&mut controller, 1013).await;
+
+    assert!(counter(dropped).await > before);
+
+    // The commands already queued are kept, the rejected one isn't queued.
+    assert_eq!(receiver.recv().await.unwrap().command(), Command::Pair);
+    assert_eq!(receiver.recv().await.unwrap().command(), Command::Play);
+    assert!(receiver.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn stuck_player_times_out() {
+    let server = spawn(
+        Config::builder()
+            .write_timeout(Duration::from_millis(200))
+            .reconnect_grace(Duration::ZERO)
+            .commands_per_second(0)
+            .max_message_size(1024 * 1024)
+            .channel_capacity(1024)
+            .build()
+            .unwrap(),
+    )
+    .await;
+
+    // The player never reads, so the server's writes pile up until the socket buffers are full.
+    let player = connect_player(&server).await;
+    let mut controller = connect_controller(&server, &player).await;
+
+    let payload = "x".repeat(512 * 1024);
+
+    for _ in 0..64 {
+        let heartbeat = json!({ "command": "Heartbeat", "payload": payload });
+
+        if controller.send(Message::text(heartbeat.to_string())).await.is_err() {
+            break;
+        }
+    }
+
+    time::timeout(TIMEOUT, async {
+        while server.state.channels.read().await.contains_key(&player.device) {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("stuck player session wasn't torn down");
+}
+
+#[tokio::test]
+async fn admin_receives_pairing_events() {
+    let server = spawn(
+        Config::builder()
+            .admin_token(Some("token".to_string()))
+            .build()
+            .unwrap(),
+    )
+    .await;
+
+    let mut request = format!("ws://{}/ws/admin", server.addr)
+        .into_client_request()
+        .unwrap();
+
+    request
+        .headers_mut()
+        .insert("authorization", "Bearer token".parse().unwrap());
+
+    let (mut admin, _) = connect_async(request).await.unwrap();
+
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    assert_eq!(
+        recv_json(&mut admin).await,
+        json!({ "event": "device_registered", "device": player.device }),
+    );
+    assert_eq!(
+        recv_json(&mut admin).await,
+        json!({ "event": "command_forwarded", "device": player.device, "command": "Pair" }),
+    );
+    assert_eq!(
+        recv_json(&mut admin).await,
+        json!({ "event": "controller_paired", "device": player.device }),
+    );
+
+    send_json(&mut controller, json!({ "command": "Unpair" })).await;
+
+    assert_eq!(
+        recv_json(&mut admin).await,
+        json!({ "event": "command_forwarded", "device": player.device, "command": "Unpair" }),
+    );
+}
+
+#[tokio::test]
+async fn admin_socket_requires_token() {
+    let server = spawn_default().await;
+
+    let err = connect_async(format!("ws://{}/ws/admin", server.addr))
+        .await
+        .unwrap_err();
+
+    let tungstenite::Error::Http(response) = err else {
+        panic!("expected an HTTP error, got {:?}", err);
+    };
+
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn registration_send_failure_is_reported() {
+    let mut failing = Box::pin(sink::unfold((), |_, _: ws::Message| async {
+        Err::<(), _>("connection reset")
+    }));
+
+    let registration = Registration {
+        device: "device".to_string(),
+        secret: Secret::from("secret"),
+        token: "token".to_string(),
+        will: None,
+    };
+
+    let result = send_registration(&mut failing, &registration, TIMEOUT).await;
+
+    assert!(matches!(result, Err(RegistrationError::Send(e)) if e == "connection reset"));
+}
+
+#[tokio::test]
+async fn reconnecting_controller_queries_state() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    send_json(&mut controller, json!({ "command": "Play", "url": VIDEO })).await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Play");
+
+    // The connection drops without a close frame, so the device stays playing.
+    drop(controller);
+
+    time::timeout(TIMEOUT, async {
+        while server.state.channels.read().await[&player.device]
+            .read()
+            .await
+            .controllers
+            > 0
+        {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("controller wasn't detached");
+
+    let path = format!(
+        "/ws/controller?device={}&secret={}&resume=true",
+        player.device, player.secret
+    );
+    let mut controller = connect(&server, &path).await;
+
+    assert_eq!(recv_json(&mut controller).await["command"], "ServerInfo");
+
+    send_json(&mut controller, json!({ "command": "GetState", "id": "1" })).await;
+
+    assert_eq!(
+        recv_json(&mut controller).await,
+        json!({ "command": "State", "state": "Played", "id": "1" }),
+    );
+}
+
+#[tokio::test]
+async fn compresses_large_responses_only() {
+    let config = Config::builder()
+        .admin_token(Some("token".to_string()))
+        .compression_min_size(1024)
+        .compression_content_types(vec!["application/json".to_string()])
+        .build()
+        .unwrap();
+
+    let state = Arc::new(State::new());
+    let router = server::router(state.clone(), &config).await;
+
+    let devices = || {
+        Request::get("/devices")
+            .header("authorization", "Bearer token")
+            .header("accept-encoding", "gzip")
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = router.clone().oneshot(devices()).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key("content-encoding"));
+
+    for _ in 0..100 {
+        state.register_device(1, 1000, IdFormat::Uuid).await.unwrap();
+    }
+
+    let response = router.oneshot(devices()).await.unwrap();
+
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+}
+
+#[tokio::test]
+async fn responses_are_not_compressed_when_disabled() {
+    let config = Config::builder()
+        .admin_token(Some("token".to_string()))
+        .compression_enabled(false)
+        .compression_min_size(0)
+        .build()
+        .unwrap();
+
+    let state = Arc::new(State::new());
+    let router = server::router(state.clone(), &config).await;
+
+    for _ in 0..100 {
+        state.register_device(1, 1000, IdFormat::Uuid).await.unwrap();
+    }
+
+    let request = Request::get("/devices")
+        .header("authorization", "Bearer token")
+        .header("accept-encoding", "gzip")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = router.oneshot(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.headers().contains_key("content-encoding"));
+}
+
+#[test]
+fn builder_validates_port_and_host() {
+    let config = Config::builder().host(" 0.0.0.0 ").port("8080").build().unwrap();
+
+    assert_eq!(config.host, "0.0.0.0");
+    assert_eq!(config.port, 8080);
+
+    for port in ["", "http", "-1", "65536"] {
+        let error = Config::builder().port(port).build().err();
+
+        assert_eq!(error, Some(ConfigError::InvalidPort(port.to_string())));
+    }
+
+    for host in ["", "   "] {
+        let error = Config::builder().host(host).build().err();
+
+        assert_eq!(error, Some(ConfigError::EmptyHost));
+    }
+}
+
+#[test]
+fn io_errors_convert_to_io_variant() {
+    let error = ServerError::from(std::io::Error::other("disk on fire"));
+
+    assert!(matches!(error, ServerError::Io(_)));
+    assert_eq!(error.to_string(), "disk on fire");
+}
+
+#[tokio::test]
+async fn serves_on_ipv4_and_ipv6_together() {
+    let config = Config::builder().build().unwrap();
+    let state = Arc::new(State::new());
+    let router = server::router(state.clone(), &config).await;
+
+    let v4 = server::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+    let v6 = server::bind("[::1]:0".parse().unwrap()).await.unwrap();
+    let addrs = [v4.local_addr().unwrap(), v6.local_addr().unwrap()];
+
+    tokio::spawn(server::run(
+        vec![v4, v6],
+        router,
+        state,
+        config.drain_timeout,
+        config.handshake_timeout,
+        future::pending(),
+    ));
+
+    for addr in addrs {
+        let (mut socket, _) = connect_async(format!("ws://{}/ws/player", addr)).await.unwrap();
+
+        assert_eq!(recv_json(&mut socket).await["command"], "ServerInfo");
+    }
+}
+
+#[tokio::test]
+async fn bind_failure_is_a_bind_error() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let error = server::bind(addr).await.unwrap_err();
+
+    assert!(matches!(error, ServerError::Bind { addr: failed, .. } if failed == addr));
+}
+
+#[tokio::test]
+async fn first_frame_is_server_info() {
+    let server = spawn_default().await;
+
+    let expected = json!({
+        "command": "ServerInfo",
+        "server_version": env!("CARGO_PKG_VERSION"),
+        "protocol_version": 1,
+        "commands": [
+            "Pair", "Unpair", "Reset", "TransferTo", "Play", "Pause", "Resume", "Seek",
+            "SetVolume", "Stop", "Capabilities", "Heartbeat", "Ping", "GetState",
+        ],
+    });
+
+    let mut player = connect(&server, "/ws/player").await;
+
+    assert_eq!(recv_json(&mut player).await, expected);
+    assert!(recv_json(&mut player).await["device"].is_string());
+
+    let mut controller = connect(&server, "/ws/controller?device=unknown&secret=secret").await;
+
+    assert_eq!(recv_json(&mut controller).await, expected);
+}
+
+#[tokio::test]
+async fn generates_ids_in_configured_format() {
+    let server = spawn(Config::builder().id_format(IdFormat::Short).build().unwrap()).await;
+    let player = connect_player(&server).await;
+
+    assert_eq!(player.device.len(), 6);
+    assert!(player.device.chars().all(|c| c.is_ascii_alphanumeric()));
+
+    let server = spawn_default().await;
+    let player = connect_player(&server).await;
+
+    assert!(uuid::Uuid::parse_str(&player.device).is_ok());
+}
+
+#[tokio::test]
+async fn short_id_collision_is_retried() {
+    let state = State::new();
+    let taken = state
+        .register_device_with(1, 10, || "ABC234".to_string())
+        .await
+        .unwrap();
+
+    let mut ids = vec!["ABC234", "ABC234", "XYZ789"].into_iter();
+    let registered = state
+        .register_device_with(1, 10, || ids.next().unwrap().to_string())
+        .await
+        .unwrap();
+
+    assert_eq!(registered.device, "XYZ789");
+    assert_eq!(
+        state.channels.read().await["ABC234"].read().await.secret,
+        taken.secret,
+    );
+}
+
+/// Pairs a controller with a new player and sends a `Play` with the URL.
+async fn play(server: &Server, url: &str) -> (Player, Socket) {
+    let mut player = connect_player(server).await;
+    let mut controller = pair(server, &mut player).await;
+
+    send_json(&mut controller, json!({ "command": "Play", "url": url })).await;
+
+    return (player, controller);
+}
+
+#[tokio::test]
+async fn play_accepts_valid_urls() {
+    let server = spawn_default().await;
+
+    for url in [VIDEO, "http://192.168.1.2:8080/movie.mkv?t=1"] {
+        let (mut player, _controller) = play(&server, url).await;
+
+        assert_eq!(recv_json(&mut player.socket).await["url"], url);
+    }
+}
+
+#[tokio::test]
+async fn play_rejects_invalid_urls() {
+    let server = spawn_default().await;
+
+    for url in ["not a url", "", "file:///etc/passwd", "ftp://example.com/video.mp4"] {
+        let (_player, mut controller) = play(&server, url).await;
+
+        match recv(&mut controller).await {
+            Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), 1002),
+            msg => panic!("expected a close frame for {:?}, got {:?}", url, msg),
+        }
+    }
+}
+
+#[tokio::test]
+async fn play_schemes_are_configurable() {
+    let server = spawn(
+        Config::builder()
+            .play_schemes(vec!["rtsp".to_string()])
+            .build()
+            .unwrap(),
+    )
+    .await;
+
+    let (mut player, _controller) = play(&server, "rtsp://camera.local/stream").await;
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Play");
+
+    let (_player, mut controller) = play(&server, VIDEO).await;
+    assert!(matches!(recv(&mut controller).await, Some(Message::Close(_))));
+}
+
+/// Writer appending to a buffer shared with the test.
+#[derive(Clone, Default)]
+pub(crate) struct Buffer(pub(crate) Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+#[tokio::test]
+async fn session_logs_carry_the_device() {
+    let buffer = Buffer::default();
+
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_writer(move || writer.clone())
+        .finish();
+
+    // The test runtime runs the server on this thread, so it logs to the test subscriber.
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let _controller = pair(&server, &mut player).await;
+
+    let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+
+    for message in ["controller paired", "command received on player side: Pair"] {
+        let line = lines
+            .iter()
+            .find(|line| line["fields"]["message"] == message)
+            .unwrap_or_else(|| panic!("no log {:?} in {}", message, output));
+
+        assert_eq!(line["span"]["name"], "session");
+        assert_eq!(line["span"]["device"], player.device);
+        assert!(line["fields"].get("device").is_none());
+    }
+}
+
+#[tokio::test]
+async fn route_logs_carry_the_client_ip() {
+    let buffer = Buffer::default();
+
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_writer(move || writer.clone())
+        .finish();
+
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let server = spawn_default().await;
+    let _player = connect_player(&server).await;
+
+    let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+
+    let line = output
+        .lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .find(|line| line["fields"]["message"] == "player route called")
+        .unwrap_or_else(|| panic!("no route log in {}", output));
+
+    assert_eq!(line["span"]["name"], "player");
+    assert_eq!(line["span"]["ip"], "127.0.0.1");
+}
+
+#[test]
+fn access_log_timestamp_is_in_common_log_format() {
+    let time = std::time::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+
+    assert_eq!(access::timestamp(time), "[09/Sep/2001:01:46:40 +0000]");
+}
+
+#[tokio::test]
+async fn access_log_records_health_requests() {
+    let buffer = Buffer::default();
+
+    let config = Config::builder()
+        .access_log(Some(Arc::new(AccessLog::new(buffer.clone()))))
+        .build()
+        .unwrap();
+
+    let router = server::router(Arc::new(State::new()), &config).await;
+
+    let response = router
+        .oneshot(
+            Request::get("/health?verbose=1")
+                .header("user-agent", "probe/1.0")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let line = output.trim_end();
+
+    assert!(line.starts_with("- - - ["), "{}", line);
+    assert!(
+        line.contains(r#"] "GET /health HTTP/1.1" 200 "#),
+        "{}",
+        line
+    );
+    assert!(line.contains(r#" "-" "probe/1.0" "#), "{}", line);
+    assert_eq!(output.lines().count(), 1);
+}
+
+#[tokio::test]
+async fn disconnects_registered_device() {
+    let config = Config::builder()
+        .admin_token(Some("token".to_string()))
+        .build()
+        .unwrap();
+
+    let state = Arc::new(State::new());
+    let router = server::router(state.clone(), &config).await;
+
+    let mut registered = state.register_device(1, 10, IdFormat::Uuid).await.unwrap();
+
+    let request = Request::delete(format!("/devices/{}", registered.device))
+        .header("authorization", "Bearer token")
+        .body(Body::empty())
+        .unwrap();
+
+    let response = router.oneshot(request).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    assert_eq!(registered.receiver.recv().await.unwrap().action, Action::Unpair);
+    assert!(!state.channels.read().await.contains_key(&registered.device));
+}
+
+#[tokio::test]
+async fn disconnecting_unknown_device_is_not_found() {
+    let config = Config::builder()
+        .admin_token(Some("token".to_string()))
+        .build()
+        .unwrap();
+
+    let router = server::router(Arc::new(State::new()), &config).await;
+
+    let request = Request::delete("/devices/unknown")
+        .header("authorization", "Bearer token")
+        .body(Body::empty())
+        .unwrap();
+
+    let (status, body) = get_json(router, request).await;
+
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"]["code"], "device_not_found");
+}
+
+#[tokio::test]
+async fn disconnecting_unresponsive_player_keeps_the_device() {
+    let config = Config::builder()
+        .admin_token(Some("token".to_string()))
+        .write_timeout(Duration::from_millis(100))
+        .build()
+        .unwrap();
+
+    let state = Arc::new(State::new());
+    let router = server::router(state.clone(), &config).await;
+
+    let registered = state.register_device(1, 10, IdFormat::Uuid).await.unwrap();
+
+    // The player isn't reading, and its only slot is taken.
+    let sender = state.channels.read().await[&registered.device].read().await.sender.clone();
+    sender.try_send(Event::new(Action::Ping)).unwrap();
+
+    let request = Request::delete(format!("/devices/{}", registered.device))
+        .header("authorization", "Bearer token")
+        .body(Body::empty())
+        .unwrap();
+
+    let (status, body) = get_json(router, request).await;
+
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["error"]["code"], "player_unreachable");
+    assert!(state.channels.read().await.contains_key(&registered.device));
+}
+
+#[tokio::test]
+async fn oversized_messages_are_rejected_without_forwarding() {
+    let server = spawn(Config::builder().max_message_size(256).build().unwrap()).await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    let url = format!("{}?padding={}", VIDEO, "a".repeat(512));
+
+    send_json(&mut controller, json!({ "command": "Play", "url": url })).await;
+
+    expect_close(&mut controller, 1002).await;
+
+    let forwarded = time::timeout(Duration::from_millis(200), recv(&mut player.socket)).await;
+    assert!(forwarded.is_err(), "oversized message reached the player: {:?}", forwarded);
+}
+
+#[tokio::test]
+async fn transport_drops_oversized_messages() {
+    let server = spawn(
+        Config::builder()
+            .max_ws_message_size(1024)
+            .max_message_size(64 * 1024)
+            .build()
+            .unwrap(),
+    )
+    .await;
+
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    let url = format!("{}?{}", VIDEO, "x".repeat(2048));
+    let play = json!({ "command": "Play", "url": url });
+
+    controller.send(Message::text(play.to_string())).await.unwrap();
+
+    assert!(matches!(recv(&mut controller).await, Some(Message::Close(_)) | None));
+
+    // The message never reaches the handler, so nothing is forwarded to the player.
+    assert!(
+        time::timeout(Duration::from_millis(200), player.socket.next())
+            .await
+            .is_err()
+    );
+}
+
+/// Observer keeping the hooks it saw, in order.
+#[derive(Default)]
+struct RecordingObserver {
+    hooks: Mutex<Vec<String>>,
+}
+
+impl Observer for RecordingObserver {
+    fn on_player_connected(&self, _: &str) {
+        self.hooks.lock().unwrap().push("player connected".to_string());
+    }
+
+    fn on_controller_paired(&self, _: &str) {
+        self.hooks.lock().unwrap().push("controller paired".to_string());
+    }
+
+    fn on_command(&self, _: &str, event: &Event) {
+        self.hooks.lock().unwrap().push(format!("{:?}", event.command()));
+    }
+
+    fn on_disconnected(&self, _: &str, peer: Peer) {
+        self.hooks.lock().unwrap().push(format!("{:?} disconnected", peer));
+    }
+}
+
+#[tokio::test]
+async fn observer_sees_session_lifecycle() {
+    let observer = Arc::new(RecordingObserver::default());
+    let state = Arc::new(State::with_observer(observer.clone()));
+    let server = spawn_with_state(state, Config::builder().build().unwrap()).await;
+
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    send_json(&mut controller, json!({ "command": "Play", "url": VIDEO })).await;
+    recv_json(&mut player.socket).await;
+
+    send_json(&mut controller, json!({ "command": "Unpair" })).await;
+    recv_json(&mut player.socket).await;
+
+    player.socket.close(None).await.unwrap();
+
+    time::timeout(TIMEOUT, async {
+        while observer.hooks.lock().unwrap().len() < 7 {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    let hooks = observer.hooks.lock().unwrap().clone();
+
+    assert_eq!(
+        hooks[..5],
+        ["player connected", "Pair", "controller paired", "Play", "Unpair"],
+    );
+
+    // Both sides wind down concurrently, so their order isn't set.
+    assert!(hooks[5..].contains(&"Player disconnected".to_string()));
+    assert!(hooks[5..].contains(&"Controller disconnected".to_string()));
+}
+
+#[tokio::test]
+async fn bind_retries_until_address_is_released() {
+    let held = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = held.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        time::sleep(Duration::from_millis(200)).await;
+
+        drop(held);
+    });
+
+    let delay = Duration::from_millis(50);
+    let listener = server::bind_with_retry(addr, 1024, Duration::ZERO, 10, delay)
+        .await
+        .unwrap();
+
+    assert_eq!(listener.local_addr().unwrap(), addr);
+}
+
+#[tokio::test]
+async fn serves_with_custom_backlog_and_keepalive() {
+    let config = Config::builder()
+        .backlog(16)
+        .tcp_keepalive(Duration::from_secs(30))
+        .build()
+        .unwrap();
+
+    let server = spawn(config).await;
+    let mut player = connect_player(&server).await;
+    let _controller = pair(&server, &mut player).await;
+
+    // The connections inherit the keepalive of the listener.
+    let local = SocketAddr::from(([127, 0, 0, 1], 0));
+    let listener = server::bind_socket(local, 16, Duration::from_secs(30)).await.unwrap();
+    let _client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (accepted, _) = listener.accept().await.unwrap();
+
+    assert!(socket2::SockRef::from(&accepted).keepalive().unwrap());
+}
+
+#[tokio::test]
+async fn bind_gives_up_after_retries() {
+    let held = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = held.local_addr().unwrap();
+
+    let delay = Duration::from_millis(10);
+    let error = server::bind_with_retry(addr, 1024, Duration::ZERO, 2, delay)
+        .await
+        .unwrap_err();
+
+    assert!(matches!(error, ServerError::Bind { addr: failed, .. } if failed == addr));
+}
+
+#[test]
+fn secret_is_redacted_when_formatted() {
+    let secret = Secret::from("hunter2");
+
+    assert_eq!(format!("{:?}", secret), "***");
+    assert_eq!(secret.to_string(), "***");
+    assert_eq!(secret.expose(), "hunter2");
+}
+
+#[test]
+fn secrets_compare_by_value() {
+    assert_eq!(Secret::from("hunter2"), Secret::from("hunter2"));
+    assert_ne!(Secret::from("hunter2"), Secret::from("hunter3"));
+    assert_ne!(Secret::from("hunter2"), Secret::from("hunter22"));
+    assert_ne!(Secret::generate(), Secret::generate());
+}
+
+#[tokio::test]
+async fn failed_registration_removes_the_device_once() {
+    let buffer = Buffer::default();
+
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_writer(move || writer.clone())
+        .finish();
+
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let auth = Arc::new(GatedAuthProvider {
+        entered: Notify::new(),
+        gate: Notify::new(),
+    });
+
+    let state: Arc<State> = Arc::new(State::with_auth(auth.clone()));
+    let server = spawn_with_state(state, Config::builder().build().unwrap()).await;
+
+    let stream = TcpStream::connect(server.addr).await.unwrap();
+    let url = format!("ws://{}/ws/player", server.addr);
+    let (socket, _) = client_async(url, stream).await.unwrap();
+
+    // The player resets the connection while its device is registered, before getting anything.
+    auth.entered.notified().await;
+
+    socket2::SockRef::from(socket.get_ref()).set_linger(Some(Duration::ZERO)).unwrap();
+    drop(socket);
+
+    time::sleep(Duration::from_millis(50)).await;
+
+    auth.gate.notify_one();
+
+    time::timeout(TIMEOUT, async {
+        while !server.state.channels.read().await.is_empty() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("device of the failed registration wasn't removed");
+
+    // Leaves time for a guard, had it stayed armed, to remove the device again.
+    time::sleep(Duration::from_millis(50)).await;
+
+    let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+
+    assert!(logs.contains("failed to send the registration message"), "{}", logs);
+    assert!(!logs.contains("interrupted session"), "{}", logs);
+}
+
+#[test]
+fn registration_exposes_secret() {
+    let registration = Registration {
+        device: "device".to_string(),
+        secret: Secret::from("hunter2"),
+        token: "token".to_string(),
+        will: None,
+    };
+
+    let value = serde_json::to_value(&registration).unwrap();
+
+    assert_eq!(value["secret"], "hunter2");
+}
+
+#[tokio::test]
+async fn commands_over_the_rate_are_dropped() {
+    let server = spawn(Config::builder().commands_per_second(3).build().unwrap()).await;
+
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    // Pairing took one of the three tokens, so only two of the heartbeats get through.
+    for i in 0..5 {
+        let heartbeat = json!({ "command": "Heartbeat", "payload": i.to_string() });
+
+        send_json(&mut controller, heartbeat).await;
+    }
+
+    send_json(&mut controller, json!({ "command": "Unpair" })).await;
+
+    assert_eq!(recv_json(&mut player.socket).await["payload"], "0");
+    assert_eq!(recv_json(&mut player.socket).await["payload"], "1");
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Unpair");
+}
+
+#[tokio::test]
+async fn rejected_commands_take_no_tokens() {
+    let server = spawn(Config::builder().commands_per_second(3).build().unwrap()).await;
+
+    let dropped = r#"commands_dropped_total{reason="invalid_state"}"#;
+    let before = counter(dropped).await;
+
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    // Nothing to resume while paired, so the resumes leave the two tokens pairing didn't take.
+    for _ in 0..5 {
+        send_json(&mut controller, json!({ "command": "Resume" })).await;
+    }
+
+    for i in 0..2 {
+        let heartbeat = json!({ "command": "Heartbeat", "payload": i.to_string() });
+
+        send_json(&mut controller, heartbeat).await;
+    }
+
+    assert_eq!(recv_json(&mut player.socket).await["payload"], "0");
+    assert_eq!(recv_json(&mut player.socket).await["payload"], "1");
+
+    assert!(counter(dropped).await >= before + 5);
+}
+
+#[test]
+fn token_bucket_refills_over_time() {
+    let mut bucket = TokenBucket::default();
+
+    assert!(bucket.take(2));
+    assert!(bucket.take(2));
+    assert!(!bucket.take(2));
+
+    std::thread::sleep(Duration::from_millis(600));
+
+    assert!(bucket.take(2));
+    assert!(bucket.take(0));
+}
+
+#[tokio::test]
+async fn batched_commands_are_forwarded_in_order() {
+    let server = spawn_default().await;
+
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    let batch = [
+        json!({ "command": "SetVolume", "level": 30 }),
+        json!({ "command": "Play", "url": VIDEO }),
+        json!({ "command": "Pause" }),
+    ]
+    .map(|event| event.to_string())
+    .join("\n");
+
+    controller.send(Message::text(batch)).await.unwrap();
+
+    assert_eq!(recv_json(&mut player.socket).await["command"], "SetVolume");
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Play");
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Pause");
+}
+
+#[tokio::test]
+async fn batch_stops_at_first_invalid_line() {
+    let server = spawn_default().await;
+
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    let batch = format!(
+        "{}\n{{not json\n{}",
+        json!({ "command": "Play", "url": VIDEO }),
+        json!({ "command": "Pause" }),
+    );
+
+    controller.send(Message::text(batch)).await.unwrap();
+
+    assert_eq!(recv_json(&mut player.socket).await["command"], "Play");
+
+    match recv(&mut controller).await {
+        Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), 1002),
+        msg => panic!("expected a close frame, got {:?}", msg),
+    }
+}
+
+#[tokio::test]
+async fn echo_mode_echoes_commands_without_player() {
+    let server = spawn(Config::builder().echo_enabled(true).build().unwrap()).await;
+
+    let mut controller = connect(&server, "/ws/controller?device=test&secret=test&echo=true").await;
+
+    assert_eq!(recv_json(&mut controller).await["command"], "ServerInfo");
+
+    // Stop is only valid once playing, so it's ignored the first time.
+    let commands = [
+        json!({ "command": "Pair", "id": "1" }),
+        json!({ "command": "Stop" }),
+        json!({ "command": "Play", "url": VIDEO }),
+        json!({ "command": "Stop" }),
+        json!({ "command": "Unpair" }),
+    ];
+
+    for command in &commands {
+        send_json(&mut controller, command.clone()).await;
+    }
+
+    for expected in [&commands[0], &commands[2], &commands[3], &commands[4]] {
+        assert_eq!(&recv_json(&mut controller).await, expected);
+    }
+
+    assert!(server.state.channels.read().await.is_empty());
+}
+
+#[tokio::test]
+async fn echo_mode_is_off_by_default() {
+    let server = spawn_default().await;
+
+    let mut controller = connect(&server, "/ws/controller?device=test&secret=test&echo=true").await;
+
+    // Handled as any controller of a device that isn't registered.
+    assert_eq!(recv_json(&mut controller).await["command"], "ServerInfo");
+    expect_close(&mut controller, 4004).await;
+}
+
+#[tokio::test]
+async fn echo_mode_checks_commands_like_a_session() {
+    let config = Config::builder()
+        .echo_enabled(true)
+        .allowed_commands(HashSet::from([Command::Pair, Command::Unpair]))
+        .build()
+        .unwrap();
+
+    let server = spawn(config).await;
+
+    let mut controller = connect(&server, "/ws/controller?device=test&secret=test&echo=true").await;
+
+    assert_eq!(recv_json(&mut controller).await["command"], "ServerInfo");
+
+    // Batched as JSON Lines, as controllers of a player may.
+    let batch = format!(
+        "{}\n{}",
+        json!({ "command": "Pair" }),
+        json!({ "command": "Play", "url": VIDEO }),
+    );
+
+    controller.send(Message::text(batch)).await.unwrap();
+
+    assert_eq!(recv_json(&mut controller).await["command"], "Pair");
+
+    expect_close(&mut controller, 1002).await;
+}
+
+#[tokio::test]
+async fn registration_never_overwrites_existing_device() {
+    let server = spawn_default().await;
+    let mut existing = connect_player(&server).await;
+
+    let mut ids = vec![existing.device.clone(), "fresh".to_string()].into_iter();
+    let registered = server
+        .state
+        .register_device_with(1, 10, || ids.next().unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(registered.device, "fresh");
+
+    // The existing player still owns its device and can be paired with.
+    let channels = server.state.channels.read().await;
+    assert_eq!(
+        channels[&existing.device].read().await.secret.expose(),
+        existing.secret,
+    );
+    drop(channels);
+
+    pair(&server, &mut existing).await;
+}
+
+#[test]
+fn actions_serialize_with_their_data() {
+    let cases = [
+        (Action::Pair { device: None }, json!({ "command": "Pair" })),
+        (
+            Action::Pair {
+                device: Some("tv".to_string()),
+            },
+            json!({ "command": "Pair", "device": "tv" }),
+        ),
+        (Action::Unpair, json!({ "command": "Unpair" })),
+        (
+            Action::Play {
+                url: VIDEO.to_string(),
+            },
+            json!({ "command": "Play", "url": VIDEO }),
+        ),
+        (Action::Pause, json!({ "command": "Pause" })),
+        (Action::Resume, json!({ "command": "Resume" })),
+        (
+            Action::Seek { position: 12.5 },
+            json!({ "command": "Seek", "position": 12.5 }),
+        ),
+        (
+            Action::SetVolume { level: 30 },
+            json!({ "command": "SetVolume", "level": 30 }),
+        ),
+        (Action::Stop, json!({ "command": "Stop" })),
+        (
+            Action::Capabilities {
+                capabilities: Some("{}".to_string()),
+            },
+            json!({ "command": "Capabilities", "capabilities": "{}" }),
+        ),
+        (
+            Action::Heartbeat { payload: None },
+            json!({ "command": "Heartbeat" }),
+        ),
+        (Action::Ack, json!({ "command": "Ack" })),
+        (
+            Action::Nack {
+                reason: Some("unsupported".to_string()),
+            },
+            json!({ "command": "Nack", "reason": "unsupported" }),
+        ),
+        (Action::GetState, json!({ "command": "GetState" })),
+        (
+            Action::State {
+                state: "Played".to_string(),
+            },
+            json!({ "command": "State", "state": "Played" }),
+        ),
+    ];
+
+    for (action, expected) in cases {
+        let event = Event::new(action.clone());
+
+        assert_eq!(serde_json::to_value(&event).unwrap(), expected);
+        assert_eq!(serde_json::from_value::<Event>(expected).unwrap().action, action);
+    }
+}
+
+#[test]
+fn actions_accept_legacy_payloads() {
+    let cases = [
+        (
+            json!({ "command": "Play", "payload": VIDEO }),
+            Action::Play {
+                url: VIDEO.to_string(),
+            },
+        ),
+        (
+            json!({ "command": "Seek", "payload": "12.5" }),
+            Action::Seek { position: 12.5 },
+        ),
+        (
+            json!({ "command": "SetVolume", "payload": "30" }),
+            Action::SetVolume { level: 30 },
+        ),
+        (
+            json!({ "command": "Stop", "payload": null }),
+            Action::Stop,
+        ),
+        (
+            json!({ "command": "Nack", "payload": "unsupported" }),
+            Action::Nack {
+                reason: Some("unsupported".to_string()),
+            },
+        ),
+    ];
+
+    for (legacy, expected) in cases {
+        assert_eq!(serde_json::from_value::<Event>(legacy).unwrap().action, expected);
+    }
+
+    let invalid = json!({ "command": "SetVolume", "payload": "loud" });
+
+    assert!(serde_json::from_value::<Event>(invalid).is_err());
+}
+
+#[test]
+fn version_1_events_are_migrated() {
+    let text = json!({ "schema_version": 1, "command": "SetVolume", "payload": "30" }).to_string();
+
+    let event = protocol::parse_message(&text).unwrap().event;
+
+    assert_eq!(event.action, Action::SetVolume { level: 30 });
+    assert_eq!(event.schema_version, 2);
+
+    // Current events don't carry their version.
+    assert_eq!(
+        serde_json::to_value(&event).unwrap(),
+        json!({ "command": "SetVolume", "level": 30 }),
+    );
+}
+
+#[tokio::test]
+async fn events_newer_than_the_schema_are_rejected() {
+    let text = json!({ "schema_version": 3, "command": "Teleport" }).to_string();
+
+    assert!(matches!(
+        protocol::parse_message(&text),
+        Err(ProtocolError::UnsupportedSchema(3)),
+    ));
+
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    controller.send(Message::text(text)).await.unwrap();
+
+    match recv(&mut controller).await {
+        Some(Message::Close(Some(frame))) => {
+            assert_eq!(u16::from(frame.code), 4015);
+            assert_eq!(frame.reason, "unsupported schema version");
+        }
+        msg => panic!("expected a close frame, got {:?}", msg),
+    }
+}
+
+#[tokio::test]
+async fn legacy_clients_are_still_forwarded() {
+    let server = spawn_default().await;
+    let mut player = connect_player(&server).await;
+    let mut controller = pair(&server, &mut player).await;
+
+    send_json(&mut controller, json!({ "command": "Play", "payload": VIDEO })).await;
+
+    assert_eq!(
+        recv_json(&mut player.socket).await,
+        json!({ "command": "Play", "url": VIDEO }),
+    );
+}
+
+#[tokio::test]
+async fn stalled_handshakes_are_dropped() {
+    let server = spawn(
+        Config::builder()
+            .handshake_timeout(Duration::from_millis(200))
+            .build()
+            .unwrap(),
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(server.addr).await.unwrap();
+
+    // The request line is sent, but never the rest of the handshake.
+    stream.write_all(b"GET /ws/player HTTP/1.1\r\n").await.unwrap();
+
+    let mut buf = [0; 1024];
+    let read = time::timeout(TIMEOUT, stream.read(&mut buf))
+        .await
+        .expect("stalled connection wasn't dropped");
+
+    assert!(matches!(read, Ok(0) | Err(_)));
+
+    // Connections completing their handshake in time are unaffected.
+    connect_player(&server).await;
+}
+
+#[tokio::test]
+async fn routes_are_mounted_under_prefix() {
+    let routes = RouteConfig {
+        prefix: "/api/v1".to_string(),
+        ..RouteConfig::default()
+    };
+    let server = spawn(Config::builder().routes(routes).build().unwrap()).await;
+
+    let mut socket = connect(&server, "/api/v1/ws/player").await;
+
+    assert_eq!(recv_json(&mut socket).await["command"], "ServerInfo");
+
+    let registration = recv_json(&mut socket).await;
+    let path = format!(
+        "/api/v1/ws/controller?device={}&secret={}",
+        registration["device"].as_str().unwrap(),
+        registration["secret"].as_str().unwrap(),
+    );
+
+    let mut controller = connect(&server, &path).await;
+
+    assert_eq!(recv_json(&mut controller).await["command"], "ServerInfo");
+
+    let err = connect_async(format!("ws://{}/ws/player", server.addr))
+        .await
+        .unwrap_err();
+
+    let tungstenite::Error::Http(response) = err else {
+        panic!("expected an HTTP error, got {:?}", err);
+    };
+
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn routes_must_be_absolute() {
+    let routes = RouteConfig {
+        prefix: "api".to_string(),
+        ..RouteConfig::default()
+    };
+
+    let error = Config::builder().routes(routes).build().err();
+
+    assert_eq!(error, Some(ConfigError::InvalidRoute("api".to_string())));
+}
+
+/// Sends the preflight of a cross-origin upgrade carrying an `Authorization` header to the
+/// controller route.
+async fn preflight(config: &Config) -> axum::response::Response {
+    let router = server::router(Arc::new(State::new()), config).await;
+
+    let request = Request::options("/ws/controller")
+        .header("origin", "https://televiu.fly.dev")
+        .header("access-control-request-method", "GET")
+        .header("access-control-request-headers", "authorization")
+        .extension(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))))
+        .body(Body::empty())
+        .unwrap();
+
+    return router.oneshot(request).await.unwrap();
+}
+
+#[tokio::test]
+async fn cors_origins_are_set_per_route() {
+    let config = Config::builder()
+        .controller_origins(vec!["https://remote.example".to_string()])
+        .player_origins(vec!["https://tv.example".to_string()])
+        .build()
+        .unwrap();
+
+    let router = server::router(Arc::new(State::new()), &config).await;
+
+    let preflight = |path: &str| {
+        Request::options(path)
+            .header("origin", "https://remote.example")
+            .header("access-control-request-method", "GET")
+            .extension(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4000))))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = router.clone().oneshot(preflight("/ws/controller")).await.unwrap();
+    assert_eq!(response.headers()["access-control-allow-origin"], "https://remote.example");
+
+    let response = router.oneshot(preflight("/ws/player")).await.unwrap();
+    assert!(!response.headers().contains_key("access-control-allow-origin"));
+}
+
+#[tokio::test]
+async fn preflight_allows_configured_headers_and_credentials() {
+    let config = Config::builder()
+        .cors_methods(vec!["get".to_string(), "DELETE".to_string()])
+        .cors_headers(vec!["Authorization".to_string()])
+        .cors_credentials(true)
+        .build()
+        .unwrap();
+
+    let response = preflight(&config).await;
+    let headers = response.headers();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(headers["access-control-allow-origin"], "https://televiu.fly.dev");
+    assert_eq!(headers["access-control-allow-headers"], "authorization");
+    assert_eq!(headers["access-control-allow-methods"], "GET,DELETE");
+    assert_eq!(headers["access-control-allow-credentials"], "true");
+
+    // By default, only GET is allowed, without extra headers or credentials.
+    let response = preflight(&Config::builder().build().unwrap()).await;
+    let headers = response.headers();
+
+    assert_eq!(headers["access-control-allow-methods"], "GET");
+    assert!(!headers.contains_key("access-control-allow-headers"));
+    assert!(!headers.contains_key("access-control-allow-credentials"));
+
+    let error = Config::builder().cors_methods(vec!["GE T".to_string()]).build().err();
+
+    assert_eq!(error, Some(ConfigError::InvalidMethod("GE T".to_string())));
+}
+
+#[test]
+fn malformed_origins_are_a_config_error() {
+    for origin in ["televiu.fly.dev", "https://televiu.fly.dev/", "https://tele viu.dev", "*"] {
+        let error = Config::builder()
+            .player_origins(vec![origin.to_string()])
+            .build()
+            .err()
+            .unwrap();
+
+        assert_eq!(error, ConfigError::InvalidOrigin(origin.to_string()));
+        assert!(error.to_string().contains(origin));
+    }
+
+    let config = Config::builder()
+        .origins(vec!["https://televiu.fly.dev".to_string(), "http://localhost:8080".to_string()])
+        .build();
+
+    assert!(config.is_ok());
+}
+
+#[tokio::test]
+async fn controller_receives_last_will_of_player() {
+    let server = spawn(Config::builder().reconnect_grace(Duration::ZERO).build().unwrap()).await;
+
+    let will = json!({ "command": "Nack", "reason": "player offline" });
+    let query: String = url::form_urlencoded::byte_serialize(will.to_string().as_bytes()).collect();
+
+    let mut socket = connect(&server, &format!("/ws/player?will={}", query)).await;
+
+    assert_eq!(recv_json(&mut socket).await["command"], "ServerInfo");
+
+    let registration = recv_json(&mut socket).await;
+
+    assert_eq!(registration["will"], will);
+
+    let mut player = Player {
+        socket,
+        device: registration["device"].as_str().unwrap().to_string(),
+        secret: registration["secret"].as_str().unwrap().to_string(),
+    };
+    let mut controller = pair(&server, &mut player).await;
+
+    player.socket.close(None).await.unwrap();
+
+    assert_eq!(recv_json(&mut controller).await, will);
+}
+
+#[tokio::test]
+async fn invalid_last_will_is_a_bad_request() {
+    let server = spawn_default().await;
+
+    let err = connect_async(format!("ws://{}/ws/player?will=nope", server.addr))
+        .await
+        .unwrap_err();
+
+    let tungstenite::Error::Http(response) = err else {
+        panic!("expected an HTTP error, got {:?}", err);
+    };
+
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+/// Provider issuing the same secret to every device, and accepting it or not.
+struct MockAuthProvider {
+    accepts: bool,
+}
+
+impl AuthProvider for MockAuthProvider {
+    fn issue<'a>(&'a self, _device: &'a str) -> BoxFuture<'a, Secret> {
+        return Box::pin(future::ready(Secret::from("issued")));
+    }
+
+    fn verify<'a>(&'a self, _device: &'a str, secret: &'a Secret) -> BoxFuture<'a, bool> {
+        return Box::pin(future::ready(self.accepts && *secret == Secret::from("issued")));
+    }
+}
+
+#[tokio::test]
+async fn auth_provider_issues_and_accepts_secrets() {
+    let state = Arc::new(State::with_auth(Arc::new(MockAuthProvider { accepts: true })));
+    let server = spawn_with_state(state, Config::builder().build().unwrap()).await;
+
+    let mut player = connect_player(&server).await;
+    assert_eq!(player.secret, "issued");
+
+    let _controller = pair(&server, &mut player).await;
+}
+
+#[tokio::test]
+async fn auth_provider_rejects_secrets() {
+    let state = Arc::new(State::with_auth(Arc::new(MockAuthProvider { accepts: false })));
+    let server = spawn_with_state(state, Config::builder().build().unwrap()).await;
+
+    let player = connect_player(&server).await;
+    let mut controller = connect_controller(&server, &player).await;
+
+    match recv(&mut controller).await {
+        Some(Message::Close(Some(frame))) => assert_eq!(u16::from(frame.code), 1008),
+        msg => panic!("expected a close frame, got {:?}", msg),
+    }
+}
+
+/// Provider issuing secrets only once let through, like one waiting on a remote store.
+struct GatedAuthProvider {
+    entered: Notify,
+    gate: Notify,
+}
+
+impl AuthProvider for GatedAuthProvider {
+    fn issue<'a>(&'a self, _device: &'a str) -> BoxFuture<'a, Secret> {
+        return Box::pin(async move {
+            self.entered.notify_one();
+            self.gate.notified().await;
+
+            return Secret::from("issued");
+        });
+    }
+
+    fn verify<'a>(&'a self, _device: &'a str, secret: &'a Secret) -> BoxFuture<'a, bool> {
+        return Box::pin(future::ready(*secret == Secret::from("issued")));
+    }
+}
+
+#[tokio::test]
+async fn secrets_are_issued_without_locking_the_registry() {
+    let auth = Arc::new(GatedAuthProvider {
+        entered: Notify::new(),
+        gate: Notify::new(),
+    });
+
+    let state: Arc<State> = Arc::new(State::with_auth(auth.clone()));
+
+    let registering = tokio::spawn({
+        let state = state.clone();
+
+        async move { return state.register_device(1, 10, IdFormat::Uuid).await.unwrap() }
+    });
+
+    auth.entered.notified().await;
+
+    // Other players and controllers can use the registry while the secret is issued.
+    assert!(state.channels.try_write().is_ok());
+
+    auth.gate.notify_one();
+
+    let registered = registering.await.unwrap();
+
+    assert_eq!(registered.secret, Secret::from("issued"));
+    assert_eq!(
+        state.channels.read().await[&registered.device].read().await.secret,
+        Secret::from("issued"),
+    );
+}